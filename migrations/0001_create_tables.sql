@@ -0,0 +1,21 @@
+-- Deployments that predate migrations already have these tables, hence IF NOT EXISTS.
+CREATE TABLE IF NOT EXISTS adminlog (
+    id SERIAL PRIMARY KEY,
+    user_id INT,
+    username VARCHAR,
+    user_msg VARCHAR,
+    timestamp TIMESTAMP WITH TIME ZONE
+);
+
+CREATE TABLE IF NOT EXISTS chanlog (
+    id SERIAL PRIMARY KEY,
+    command VARCHAR,
+    target VARCHAR,
+    user_id INT,
+    user_type VARCHAR,
+    username VARCHAR,
+    sub_count INT,
+    system_msg VARCHAR,
+    user_msg VARCHAR,
+    timestamp TIMESTAMP WITH TIME ZONE
+);
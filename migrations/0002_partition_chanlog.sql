@@ -0,0 +1,48 @@
+-- chanlog becomes a table partitioned by month. Rows from before the month this migration runs
+-- in are not moved: the old table is attached as the partition for everything before that
+-- month. This month's rows are moved to the monthly partitions db.rs would otherwise create,
+-- and db.rs creates a partition for each month from then on.
+ALTER TABLE chanlog RENAME TO chanlog_legacy;
+-- Replaced by the (id, "timestamp") key the partitioned table requires when it is attached.
+ALTER TABLE chanlog_legacy DROP CONSTRAINT chanlog_pkey;
+ALTER TABLE chanlog_legacy ALTER COLUMN "timestamp" SET NOT NULL;
+
+CREATE TABLE chanlog (LIKE chanlog_legacy INCLUDING DEFAULTS) PARTITION BY RANGE ("timestamp");
+ALTER TABLE chanlog ADD PRIMARY KEY (id, "timestamp");
+ALTER SEQUENCE chanlog_id_seq OWNED BY chanlog.id;
+
+-- Catches rows for months that have no partition yet, e.g. journal segments replayed late.
+CREATE TABLE chanlog_default PARTITION OF chanlog DEFAULT;
+
+-- Months are counted in UTC, whatever the session's time zone.
+DO $$
+DECLARE
+    boundary TIMESTAMP := date_trunc('month', now() AT TIME ZONE 'UTC');
+    first TIMESTAMP;
+BEGIN
+    -- Named and bounded as in db.rs `ensure_partitions`: this month and the next.
+    FOR i IN 0..1 LOOP
+        first := boundary + make_interval(months => i);
+
+        EXECUTE format(
+            'CREATE TABLE chanlog_%s PARTITION OF chanlog FOR VALUES FROM (%L) TO (%L)',
+            to_char(first, 'YYYY_MM'),
+            first AT TIME ZONE 'UTC',
+            (first + interval '1 month') AT TIME ZONE 'UTC'
+        );
+    END LOOP;
+
+    -- Rows at or past the boundary would fail the legacy partition's constraint on attach.
+    WITH moved AS (
+        DELETE FROM chanlog_legacy WHERE "timestamp" >= boundary AT TIME ZONE 'UTC' RETURNING *
+    )
+    INSERT INTO chanlog SELECT * FROM moved;
+
+    EXECUTE format(
+        'ALTER TABLE chanlog ATTACH PARTITION chanlog_legacy FOR VALUES FROM (MINVALUE) TO (%L)',
+        boundary AT TIME ZONE 'UTC'
+    );
+END $$;
+
+CREATE INDEX chanlog_target_timestamp_idx ON chanlog (target, "timestamp");
+CREATE INDEX chanlog_user_id_timestamp_idx ON chanlog (user_id, "timestamp");
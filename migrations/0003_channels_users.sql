@@ -0,0 +1,26 @@
+-- Channels and users are keyed by their Twitch ids; the name columns hold the latest name seen.
+CREATE TABLE channels (
+    room_id INT PRIMARY KEY,
+    name VARCHAR NOT NULL,
+    first_seen TIMESTAMP WITH TIME ZONE NOT NULL,
+    last_seen TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+CREATE TABLE users (
+    user_id INT PRIMARY KEY,
+    username VARCHAR NOT NULL,
+    first_seen TIMESTAMP WITH TIME ZONE NOT NULL,
+    last_seen TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+-- Rows logged so far carry no room id, but every user id they reference can be backfilled.
+INSERT INTO users (user_id, username, first_seen, last_seen)
+SELECT user_id, (array_agg(username ORDER BY "timestamp" DESC))[1], min("timestamp"), max("timestamp")
+FROM chanlog
+WHERE user_id IS NOT NULL
+GROUP BY user_id;
+
+ALTER TABLE chanlog ADD COLUMN room_id INT REFERENCES channels (room_id);
+ALTER TABLE chanlog ADD FOREIGN KEY (user_id) REFERENCES users (user_id);
+
+CREATE INDEX chanlog_room_id_timestamp_idx ON chanlog (room_id, "timestamp");
@@ -0,0 +1,18 @@
+-- Every (login, display name) pair observed for a user id, so renames can be traced.
+CREATE TABLE user_names (
+    user_id INT NOT NULL REFERENCES users (user_id),
+    login VARCHAR NOT NULL,
+    display_name VARCHAR NOT NULL,
+    first_seen TIMESTAMP WITH TIME ZONE NOT NULL,
+    last_seen TIMESTAMP WITH TIME ZONE NOT NULL,
+    PRIMARY KEY (user_id, login, display_name)
+);
+
+CREATE INDEX user_names_login_idx ON user_names (login);
+
+-- Display names were never logged, so backfilled rows only carry the login.
+INSERT INTO user_names (user_id, login, display_name, first_seen, last_seen)
+SELECT user_id, username, '', min("timestamp"), max("timestamp")
+FROM chanlog
+WHERE user_id IS NOT NULL AND username IS NOT NULL
+GROUP BY user_id, username;
@@ -0,0 +1,5 @@
+-- The Twitch message id. The unique index has to include the partition key, which is why
+-- messages are timestamped with the time Twitch sent them rather than when they arrived.
+ALTER TABLE chanlog ADD COLUMN msg_id VARCHAR;
+
+CREATE UNIQUE INDEX chanlog_msg_id_timestamp_idx ON chanlog (msg_id, "timestamp");
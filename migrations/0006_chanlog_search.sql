@@ -0,0 +1,5 @@
+-- Full-text search uses an expression index on to_tsvector('simple', user_msg), which db.rs
+-- builds in the background after startup. Building it here would hold up startup until every
+-- existing row was indexed. The `simple` configuration skips stemming and stop words, which
+-- suit chat in any language better than the English defaults.
+SELECT 1;
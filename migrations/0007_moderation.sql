@@ -0,0 +1,18 @@
+-- Deletions (CLEARMSG), timeouts and bans (CLEARCHAT naming a user) and chat clears (CLEARCHAT
+-- without one). `username` is empty for chat clears and `ban_duration` is 0 unless the event
+-- was a timeout. Twitch only sends a login with CLEARMSG, so there is no reference to `users`.
+CREATE TABLE moderation (
+    id SERIAL PRIMARY KEY,
+    command VARCHAR NOT NULL,
+    target VARCHAR NOT NULL,
+    room_id INT,
+    user_id INT,
+    username VARCHAR NOT NULL,
+    target_msg_id VARCHAR,
+    ban_duration INT NOT NULL,
+    user_msg VARCHAR NOT NULL,
+    "timestamp" TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+-- Also skips events logged again by a second connection, which see the same `tmi-sent-ts`.
+CREATE UNIQUE INDEX moderation_event_idx ON moderation (target, "timestamp", command, username);
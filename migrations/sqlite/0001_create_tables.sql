@@ -0,0 +1,53 @@
+-- SQLite mirrors the Postgres schema as of 0005, without partitioning. Timestamps are stored as
+-- UTC ISO 8601 text, which sorts chronologically.
+CREATE TABLE adminlog (
+    id INTEGER PRIMARY KEY,
+    user_id INT,
+    username TEXT,
+    user_msg TEXT,
+    timestamp TEXT
+);
+
+CREATE TABLE channels (
+    room_id INT PRIMARY KEY,
+    name TEXT NOT NULL,
+    first_seen TEXT NOT NULL,
+    last_seen TEXT NOT NULL
+);
+
+CREATE TABLE users (
+    user_id INT PRIMARY KEY,
+    username TEXT NOT NULL,
+    first_seen TEXT NOT NULL,
+    last_seen TEXT NOT NULL
+);
+
+CREATE TABLE chanlog (
+    id INTEGER PRIMARY KEY,
+    msg_id TEXT UNIQUE,
+    command TEXT,
+    target TEXT,
+    room_id INT REFERENCES channels (room_id),
+    user_id INT REFERENCES users (user_id),
+    user_type TEXT,
+    username TEXT,
+    sub_count INT,
+    system_msg TEXT,
+    user_msg TEXT,
+    timestamp TEXT NOT NULL
+);
+
+CREATE INDEX chanlog_target_timestamp_idx ON chanlog (target, timestamp);
+CREATE INDEX chanlog_user_id_timestamp_idx ON chanlog (user_id, timestamp);
+CREATE INDEX chanlog_room_id_timestamp_idx ON chanlog (room_id, timestamp);
+
+CREATE TABLE user_names (
+    user_id INT NOT NULL REFERENCES users (user_id),
+    login TEXT NOT NULL,
+    display_name TEXT NOT NULL,
+    first_seen TEXT NOT NULL,
+    last_seen TEXT NOT NULL,
+    PRIMARY KEY (user_id, login, display_name)
+);
+
+CREATE INDEX user_names_login_idx ON user_names (login);
@@ -0,0 +1,15 @@
+-- Mirrors Postgres migration 0007.
+CREATE TABLE moderation (
+    id INTEGER PRIMARY KEY,
+    command TEXT NOT NULL,
+    target TEXT NOT NULL,
+    room_id INT,
+    user_id INT,
+    username TEXT NOT NULL,
+    target_msg_id TEXT,
+    ban_duration INT NOT NULL,
+    user_msg TEXT NOT NULL,
+    timestamp TEXT NOT NULL
+);
+
+CREATE UNIQUE INDEX moderation_event_idx ON moderation (target, timestamp, command, username);
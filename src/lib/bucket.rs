@@ -0,0 +1,70 @@
+use std::time;
+
+// A token bucket allowing `capacity` takes per `window`. It starts full and refills evenly.
+pub struct Bucket {
+    capacity: f64,
+    per_second: f64,
+    tokens: f64,
+    updated: time::Instant,
+}
+
+impl Bucket {
+    pub fn new(capacity: u32, window: time::Duration) -> Self {
+        let capacity = f64::from(capacity.max(1));
+
+        Self {
+            capacity,
+            per_second: capacity / window.as_secs_f64(),
+            tokens: capacity,
+            updated: time::Instant::now(),
+        }
+    }
+
+    // Takes a token, or returns how long until the next one.
+    pub fn take(&mut self) -> Result<(), time::Duration> {
+        let now = time::Instant::now();
+        let refilled = now.duration_since(self.updated).as_secs_f64() * self.per_second;
+
+        self.tokens = (self.tokens + refilled).min(self.capacity);
+        self.updated = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(time::Duration::from_secs_f64((1.0 - self.tokens) / self.per_second))
+        }
+    }
+
+    pub async fn wait(&mut self) {
+        while let Err(delay) = self.take() {
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_takes_per_window() {
+        let mut bucket = Bucket::new(2, time::Duration::from_secs(10));
+
+        assert!(bucket.take().is_ok());
+        assert!(bucket.take().is_ok());
+
+        let delay = bucket.take().unwrap_err();
+
+        assert!(delay > time::Duration::from_secs(4) && delay <= time::Duration::from_secs(5));
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let mut bucket = Bucket::new(1, time::Duration::from_millis(20));
+
+        assert!(bucket.take().is_ok());
+        std::thread::sleep(time::Duration::from_millis(25));
+        assert!(bucket.take().is_ok());
+    }
+}
@@ -1,8 +1,7 @@
 use chrono::prelude::*;
-use irc::client::prelude::*;
-use std::{thread, time};
+use std::sync::atomic::Ordering;
 
-use crate::lib::{config, db, error, file, message};
+use crate::lib::{config, error, file, index, message, search, shard, store};
 
 fn check_prefix(mut channels: Vec<String>) -> Vec<String> {
     for channel in &mut channels {
@@ -21,19 +20,17 @@ fn format_channels(channels: Vec<String>) -> String {
 }
 
 pub fn parse_cmd(
-    client: &irc::client::IrcClient,
+    client: &shard::Shards,
     bot_state: std::sync::MutexGuard<config::State>,
+    writer: Option<&store::Writer>,
     parsed_msg: &message::Message,
 ) -> Result<(), error::Error> {
     let config = config::Config::load()?;
 
     file::Logger::save_admin_txt(parsed_msg)?;
 
-    if bot_state.postgres {
-        match db::insert_admin_log(parsed_msg.to_owned()) {
-            Ok(_) => {}
-            Err(e) => eprintln!("{}", e),
-        };
+    if let Some(writer) = writer {
+        writer.insert_admin_log(parsed_msg.to_owned());
     };
 
     let mut args: Vec<String> = parsed_msg.user_msg.split(' ').map(str::to_lowercase).collect();
@@ -53,14 +50,22 @@ pub fn parse_cmd(
                 list(client, config, &parsed_msg.username)?;
             }
             "uptime" | "status" => {
-                uptime(client, &bot_state, &config, &parsed_msg.username)?;
+                uptime(client, &bot_state, &parsed_msg.username)?;
+            }
+            "aliases" | "names" => {
+                args.remove(0);
+                aliases(client, writer, &parsed_msg.username, &args)?;
+            }
+            "search" | "find" => {
+                args.remove(0);
+                search(client, writer, &parsed_msg.username, &args)?;
             }
             "buffer" => {
                 args.remove(0);
-                buffer(client, bot_state, &config, &parsed_msg.username, &args)?;
+                buffer(client, bot_state, &parsed_msg.username, &args)?;
             }
             "pause" | "stop" | "unpause" | "start" => {
-                pause(client, bot_state, &config, &parsed_msg.username, sub_cmd)?;
+                pause(client, bot_state, &parsed_msg.username, sub_cmd)?;
             }
             "shutdown" | "exit" | "quit" => {
                 panic!("Bot shutdown by {} at {}", &parsed_msg.username, Utc::now());
@@ -73,34 +78,36 @@ pub fn parse_cmd(
 }
 
 fn join(
-    client: &irc::client::IrcClient,
+    client: &shard::Shards,
     mut bot_state: std::sync::MutexGuard<config::State>,
     mut config: config::Config,
     admin: &str,
     channels: Vec<String>,
 ) -> Result<(), error::Error> {
-    let channels = check_prefix(channels);
+    let (channels, invalid): (Vec<String>, Vec<String>) =
+        check_prefix(channels).into_iter().partition(|x| file::valid_channel(x));
     let mut v = Vec::new();
 
+    if !invalid.is_empty() {
+        client.whisper(admin, &format!("Invalid channel names: {}", format_channels(invalid)))?;
+    }
+
     file::create_dirs(&channels)?;
 
-    for channel in &channels {
-        if !config.channels.contains(channel) && client.send_join(channel).is_ok() {
-            v.push(channel.to_string());
-            config.channels.push(channel.to_string());
+    for channel in channels {
+        if !config.channels.contains(&channel) && !v.contains(&channel) {
+            v.push(channel);
             bot_state.buffer += 10;
         }
-        thread::sleep(time::Duration::from_millis(320));
     }
 
-    config.channels.sort();
-
+    // Joins are rate limited, so they run in the background and report back when done.
     if !v.is_empty() {
-        client.send(Command::Raw(
-            format!("PRIVMSG {} :/w {} Joined: {}", config.nickname, admin, format_channels(v)),
-            vec![],
-            None,
-        ))?;
+        config.channels.extend(v.iter().cloned());
+        config.channels.sort();
+
+        client.whisper(admin, &format!("Joining: {}", format_channels(v.clone())))?;
+        client.queue_join(v, admin);
     }
 
     config::Config::update(config)?;
@@ -109,7 +116,7 @@ fn join(
 }
 
 fn part(
-    client: &irc::client::IrcClient,
+    client: &shard::Shards,
     mut bot_state: std::sync::MutexGuard<config::State>,
     mut config: config::Config,
     admin: &str,
@@ -119,7 +126,7 @@ fn part(
     let mut v = Vec::new();
 
     for channel in &channels {
-        if config.channels.contains(channel) && client.send_part(channel).is_ok() {
+        if config.channels.contains(channel) && client.send_part(channel, admin).is_ok() {
             v.push(channel.to_string());
             config.channels.retain(|x| x != channel);
             bot_state.buffer -= 10;
@@ -127,11 +134,7 @@ fn part(
     }
 
     if !v.is_empty() {
-        client.send(Command::Raw(
-            format!("PRIVMSG {} :/w {} Left: {}", config.nickname, admin, format_channels(v)),
-            vec![],
-            None,
-        ))?;
+        client.whisper(admin, &format!("Left: {}", format_channels(v)))?;
     }
 
     config::Config::update(config)?;
@@ -139,64 +142,28 @@ fn part(
     Ok(())
 }
 
-fn list(
-    client: &irc::client::IrcClient,
-    config: config::Config,
-    admin: &str,
-) -> Result<(), error::Error> {
+// Long lists are split across whispers by the outbox.
+fn list(client: &shard::Shards, config: config::Config, admin: &str) -> Result<(), error::Error> {
     let count = config.channels.len();
 
     match count {
-        0 => {
-            client.send(Command::Raw(
-                format!("PRIVMSG {} :/w {} Bot is logging 0 channels", config.nickname, admin),
-                vec![],
-                None,
-            ))?;
-        }
-        1 => {
-            client.send(Command::Raw(
-                format!(
-                    "PRIVMSG {} :/w {} Bot is logging 1 channel: {}",
-                    config.nickname,
-                    admin,
-                    format_channels(config.channels)
-                ),
-                vec![],
-                None,
-            ))?;
-        }
-        _ => {
-            client.send(Command::Raw(
-                format!(
-                    "PRIVMSG {} :/w {} Bot is logging {} channels:",
-                    config.nickname, admin, count
-                ),
-                vec![],
-                None,
-            ))?;
-
-            let mut channels = config.channels.into_iter().peekable();
-
-            while channels.peek().is_some() {
-                let chunk = channels.by_ref().take(45).collect();
-
-                client.send(Command::Raw(
-                    format!("PRIVMSG {} :/w {} {}", config.nickname, admin, format_channels(chunk)),
-                    vec![],
-                    None,
-                ))?;
-            }
-        }
+        0 => client.whisper(admin, "Bot is logging 0 channels")?,
+        1 => client.whisper(
+            admin,
+            &format!("Bot is logging 1 channel: {}", format_channels(config.channels)),
+        )?,
+        _ => client.whisper(
+            admin,
+            &format!("Bot is logging {} channels: {}", count, format_channels(config.channels)),
+        )?,
     }
 
     Ok(())
 }
 
 fn uptime(
-    client: &irc::client::IrcClient,
+    client: &shard::Shards,
     bot_state: &config::State,
-    config: &config::Config,
     admin: &str,
 ) -> Result<(), error::Error> {
     let current_time = Utc::now();
@@ -207,54 +174,130 @@ fn uptime(
     formatter.ago("");
 
     let uptime = formatter.convert_chrono(start_time, current_time);
-
-    client.send(Command::Raw(
+    let rows = if bot_state.database {
         format!(
-            "PRIVMSG {} :/w {} Bot uptime: {} | Bot buffer: {}",
-            config.nickname, admin, uptime, bot_state.buffer
-        ),
-        vec![],
-        None,
-    ))?;
+            " | Pending rows: {} | Spilled batches: {} | Dropped rows: {}",
+            store::PENDING.load(Ordering::Relaxed),
+            store::SPILLED.load(Ordering::Relaxed),
+            store::DROPPED.load(Ordering::Relaxed)
+        )
+    } else {
+        String::new()
+    };
+
+    client.whisper(
+        admin,
+        &format!("Bot uptime: {} | Bot buffer: {}{}", uptime, bot_state.buffer, rows),
+    )?;
+
+    Ok(())
+}
+
+fn aliases(
+    client: &shard::Shards,
+    writer: Option<&store::Writer>,
+    admin: &str,
+    args: &[String],
+) -> Result<(), error::Error> {
+    let reply = match (writer, args.first()) {
+        (None, _) => String::from("Aliases are only recorded when a database is enabled"),
+        (_, None) => String::from("A username is required"),
+        (Some(writer), Some(user)) => {
+            let user = user.trim_start_matches('@');
+
+            match writer.store.aliases(user) {
+                Ok(aliases) if aliases.is_empty() => format!("No names recorded for {}", user),
+                Ok(aliases) => {
+                    let names: Vec<String> = aliases
+                        .iter()
+                        .map(|x| {
+                            let name = if x.display_name.is_empty()
+                                || x.display_name.to_lowercase() == x.login
+                            {
+                                x.login.clone()
+                            } else {
+                                format!("{} ({})", x.login, x.display_name)
+                            };
+
+                            format!(
+                                "{} {} to {}",
+                                name,
+                                x.first_seen.format("%Y-%m-%d"),
+                                x.last_seen.format("%Y-%m-%d")
+                            )
+                        })
+                        .collect();
+
+                    format!("Known names for {}: {}", user, names.join(" | "))
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
+                    format!("Unable to look up names for {}", user)
+                }
+            }
+        }
+    };
+
+    client.whisper(admin, &reply)?;
+
+    Ok(())
+}
+
+// Whispers are single lines, so only the newest few matches are sent back.
+const SEARCH_REPLY_HITS: u32 = 3;
+
+fn search(
+    client: &shard::Shards,
+    writer: Option<&store::Writer>,
+    admin: &str,
+    args: &[String],
+) -> Result<(), error::Error> {
+    // Without a database, text logs are indexed instead.
+    let hits = search::Query::parse(args).map(|query| {
+        writer.map_or_else(
+            || {
+                index::search(&query, SEARCH_REPLY_HITS)
+                    .map(|x| x.into_iter().map(|found| found.hit).collect())
+            },
+            |writer| writer.store.search(&query, SEARCH_REPLY_HITS),
+        )
+    });
+
+    let reply = match hits {
+        Err(e) => e,
+        Ok(Ok(hits)) if hits.is_empty() => String::from("No matching messages"),
+        Ok(Ok(hits)) => {
+            let lines: Vec<String> = hits.iter().map(search::Hit::line).collect();
+
+            lines.join(" | ")
+        }
+        Ok(Err(e)) => {
+            eprintln!("{}", e);
+            String::from("Unable to search messages")
+        }
+    };
+
+    client.whisper(admin, &reply)?;
 
     Ok(())
 }
 
 fn buffer(
-    client: &irc::client::IrcClient,
+    client: &shard::Shards,
     mut bot_state: std::sync::MutexGuard<config::State>,
-    config: &config::Config,
     admin: &str,
     args: &[String],
 ) -> Result<(), error::Error> {
     if args.is_empty() {
-        client.send(Command::Raw(
-            format!("PRIVMSG {} :/w {} An integer value is required", config.nickname, admin),
-            vec![],
-            None,
-        ))?;
+        client.whisper(admin, "An integer value is required")?;
     } else {
         match args[0].parse::<usize>() {
             Ok(val) => {
                 bot_state.buffer = val;
-                client.send(Command::Raw(
-                    format!(
-                        "PRIVMSG {} :/w {} Bot buffer set to {}",
-                        config.nickname, admin, bot_state.buffer
-                    ),
-                    vec![],
-                    None,
-                ))?;
+                client.whisper(admin, &format!("Bot buffer set to {}", bot_state.buffer))?;
             }
             Err(_) => {
-                client.send(Command::Raw(
-                    format!(
-                        "PRIVMSG {} :/w {} An integer value is required",
-                        config.nickname, admin
-                    ),
-                    vec![],
-                    None,
-                ))?;
+                client.whisper(admin, "An integer value is required")?;
             }
         }
     }
@@ -263,28 +306,19 @@ fn buffer(
 }
 
 fn pause(
-    client: &irc::client::IrcClient,
+    client: &shard::Shards,
     mut bot_state: std::sync::MutexGuard<config::State>,
-    config: &config::Config,
     admin: &str,
     sub_cmd: &str,
 ) -> Result<(), error::Error> {
     if sub_cmd == "pause" || sub_cmd == "stop" {
         bot_state.paused = true;
 
-        client.send(Command::Raw(
-            format!("PRIVMSG {} :/w {} Bot logging is now paused", config.nickname, admin),
-            vec![],
-            None,
-        ))?;
+        client.whisper(admin, "Bot logging is now paused")?;
     } else {
         bot_state.paused = false;
 
-        client.send(Command::Raw(
-            format!("PRIVMSG {} :/w {} Bot logging is now unpaused", config.nickname, admin),
-            vec![],
-            None,
-        ))?;
+        client.whisper(admin, "Bot logging is now unpaused")?;
     }
 
     Ok(())
@@ -1,4 +1,6 @@
+use chrono::format::{Item, StrftimeItems};
 use chrono::prelude::*;
+use std::collections::BTreeMap;
 use std::fs;
 use std::io::{BufWriter, Write};
 
@@ -8,12 +10,12 @@ use crate::lib::error;
 pub struct State {
     pub buffer: usize,
     pub paused: bool,
-    pub postgres: bool,
+    pub database: bool,
     pub uptime: chrono::DateTime<Utc>,
 }
 
 impl State {
-    pub fn new(count: usize, postgres: bool) -> Self {
+    pub fn new(count: usize, database: bool) -> Self {
         let buffer = {
             if count <= 10 {
                 100
@@ -22,25 +24,145 @@ impl State {
             }
         };
 
-        Self { buffer, paused: false, postgres, uptime: Utc::now() }
+        Self { buffer, paused: false, database, uptime: Utc::now() }
     }
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct Templates {
+    pub timestamp: String,
+    pub message: String,
+    pub notice: String,
+    pub subscription: String,
+}
+
+impl Default for Templates {
+    fn default() -> Self {
+        Self {
+            timestamp: String::from("%Y-%m-%d %H:%M:%S"),
+            message: String::from("{timestamp} {badges}[{sub_months}] {user}: {message}"),
+            notice: String::from("{timestamp} - [Notice] {system_msg}"),
+            subscription: String::from("{timestamp} - [Subscription Message] {message}"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SinkKind {
+    Text,
+    Database,
+}
+
+impl SinkKind {
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Text => "text",
+            Self::Database => "database",
+        }
+    }
+}
+
+// Channels listed under `channels` use their own sinks; every other channel uses `default`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct Sinks {
+    pub default: Vec<SinkKind>,
+    pub channels: BTreeMap<String, Vec<SinkKind>>,
+}
+
+impl Default for Sinks {
+    fn default() -> Self {
+        Self { default: vec![SinkKind::Text, SinkKind::Database], channels: BTreeMap::new() }
+    }
+}
+
+impl Sinks {
+    pub fn for_channel(&self, channel: &str) -> &[SinkKind] {
+        self.channels
+            .iter()
+            .find(|(name, _)| name.trim_start_matches('#') == channel.trim_start_matches('#'))
+            .map_or(&self.default, |(_, sinks)| sinks)
+    }
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Storage {
+    #[default]
+    Postgres,
+    Sqlite,
+}
+
+const fn default_tls() -> bool {
+    true
+}
+
+const fn default_channels_per_connection() -> usize {
+    50
+}
+
+// Twitch allows 20 joins every 10 seconds, or 2,000 for verified bots.
+const fn default_join_limit() -> u32 {
+    20
+}
+
+fn default_sqlite() -> String {
+    String::from("logs/chanlog.db")
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Config {
     pub nickname: String,
     pub oauth: String,
     pub server: String,
+    #[serde(default)]
+    pub port: u16,
+    #[serde(default = "default_tls")]
+    pub tls: bool,
+    #[serde(default = "default_channels_per_connection")]
+    pub channels_per_connection: usize,
+    #[serde(default = "default_join_limit")]
+    pub join_limit: u32,
+    #[serde(default)]
+    pub storage: Storage,
+    #[serde(default)]
     pub postgres: String,
+    #[serde(default = "default_sqlite")]
+    pub sqlite: String,
     pub admins: Vec<String>,
     pub channels: Vec<String>,
+    #[serde(default)]
+    pub templates: Templates,
+    #[serde(default)]
+    pub sinks: Sinks,
+    #[serde(default)]
+    pub http: String,
 }
 
 impl Config {
+    // Twitch serves IRC over TLS on 6697 and in plain text on 6667.
+    pub const fn port(&self) -> u16 {
+        match (self.port, self.tls) {
+            (0, true) => 6697,
+            (0, false) => 6667,
+            (port, _) => port,
+        }
+    }
+
     pub fn load() -> Result<Self, error::Error> {
         let file = fs::OpenOptions::new().read(true).open("config.json")?;
         let json: Self = serde_json::from_reader(file)?;
 
+        // An invalid format would otherwise fail on every line written.
+        if StrftimeItems::new(&json.templates.timestamp).any(|x| x == Item::Error) {
+            return Err(error::Error::Config(format!(
+                "Invalid timestamp template: {}",
+                json.templates.timestamp
+            )));
+        }
+
         Ok(json)
     }
 
@@ -1,83 +1,724 @@
-use postgres::{Client, NoTls};
-use std::thread;
+use chrono::prelude::*;
+use postgres::binary_copy::BinaryCopyInWriter;
+use postgres::types::Type;
+use postgres::NoTls;
+use r2d2_postgres::PostgresConnectionManager;
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Once;
+use std::{thread, time};
 
-use crate::lib::{config, error, message};
+use crate::lib::{error, message, search, store};
 
-fn connect() -> Result<postgres::Client, error::Error> {
-    let config = config::Config::load()?;
-    let db = Client::connect(&config.postgres, NoTls)?;
+const POOL_SIZE: u32 = 4;
+const POOL_TIMEOUT: time::Duration = time::Duration::from_secs(10);
 
-    Ok(db)
+pub type Pool = r2d2::Pool<PostgresConnectionManager<NoTls>>;
+
+// Connections are validated on checkout and replaced when they fail, so callers never hold a
+// connection that Postgres has already dropped. They are opened lazily, which keeps an
+// unreachable database from blocking startup.
+pub fn pool(url: &str) -> Result<Pool, error::Error> {
+    let manager = PostgresConnectionManager::new(url.parse()?, NoTls);
+    let pool = r2d2::Pool::builder()
+        .max_size(POOL_SIZE)
+        .connection_timeout(POOL_TIMEOUT)
+        .test_on_check_out(true)
+        .build_unchecked(manager);
+
+    Ok(pool)
 }
 
-pub fn create_tables() -> Result<(), error::Error> {
-    let mut db = connect()?;
+// Migrations are applied in order, each in its own transaction, and recorded in
+// `schema_version`. Never edit one that has shipped; append a new one instead.
+const MIGRATIONS: &[(i32, &str)] = &[
+    (1, include_str!("../../migrations/0001_create_tables.sql")),
+    (2, include_str!("../../migrations/0002_partition_chanlog.sql")),
+    (3, include_str!("../../migrations/0003_channels_users.sql")),
+    (4, include_str!("../../migrations/0004_user_names.sql")),
+    (5, include_str!("../../migrations/0005_chanlog_msg_id.sql")),
+    (6, include_str!("../../migrations/0006_chanlog_search.sql")),
+    (7, include_str!("../../migrations/0007_moderation.sql")),
+];
 
-    db.execute(
-        "CREATE TABLE IF NOT EXISTS adminlog (
-            id SERIAL PRIMARY KEY,
-            user_id INT,
-            username VARCHAR,
-            user_msg VARCHAR,
-            timestamp TIMESTAMP WITH TIME ZONE
-        );",
+// Arbitrary keys for the advisory locks that keep two instances from migrating, or building
+// the search index, at once.
+const MIGRATION_LOCK: i64 = 0x7477_6c62;
+const SEARCH_INDEX_LOCK: i64 = 0x7477_6c63;
+
+// Migrations are retried whenever the bot reconnects, but the index only needs one builder.
+static SEARCH_INDEX: Once = Once::new();
+
+fn migrate(pool: &Pool) -> Result<(), error::Error> {
+    let mut db = pool.get()?;
+
+    db.execute("SELECT pg_advisory_lock($1)", &[&MIGRATION_LOCK])?;
+
+    let result = apply_migrations(&mut db);
+
+    db.execute("SELECT pg_advisory_unlock($1)", &[&MIGRATION_LOCK])?;
+
+    result?;
+    ensure_partitions(pool)?;
+
+    SEARCH_INDEX.call_once(|| {
+        let pool = pool.clone();
+
+        thread::spawn(move || {
+            if let Err(e) = build_search_index(&pool) {
+                eprintln!("Unable to build the search index: {}", e);
+            }
+        });
+    });
+
+    Ok(())
+}
+
+// Must match the expression in `search` for the index to be used.
+const SEARCH_EXPRESSION: &str = "to_tsvector('simple', coalesce(user_msg, ''))";
+
+// Partitioned tables can't be indexed concurrently, so the index is created on `chanlog` alone,
+// built concurrently on each partition and attached, which makes it valid once every partition
+// has one. Partitions created later get their index with the table. Builds interrupted by a
+// restart leave an invalid index behind, which is dropped and built again. Another instance
+// already building it would have its index dropped too, so only the lock holder builds.
+fn build_search_index(pool: &Pool) -> Result<(), error::Error> {
+    let mut db = pool.get()?;
+
+    if !db.query_one("SELECT pg_try_advisory_lock($1)", &[&SEARCH_INDEX_LOCK])?.get::<_, bool>(0) {
+        return Ok(());
+    }
+
+    let result = build_partition_indexes(&mut db);
+
+    db.execute("SELECT pg_advisory_unlock($1)", &[&SEARCH_INDEX_LOCK])?;
+
+    result
+}
+
+fn build_partition_indexes(db: &mut postgres::Client) -> Result<(), error::Error> {
+    db.batch_execute(&format!(
+        "CREATE INDEX IF NOT EXISTS chanlog_user_msg_tsv_idx ON ONLY chanlog USING GIN ({})",
+        SEARCH_EXPRESSION
+    ))?;
+
+    // Partitions created after the index already have one attached, under their own name.
+    let partitions = db.query(
+        "SELECT c.relname, EXISTS (
+                SELECT 1 FROM pg_inherits pi
+                JOIN pg_index x ON x.indexrelid = pi.inhrelid
+                WHERE pi.inhparent = 'chanlog_user_msg_tsv_idx'::regclass AND x.indrelid = c.oid
+            ), (
+                SELECT x.indisvalid FROM pg_index x
+                WHERE x.indexrelid = to_regclass(c.relname || '_user_msg_tsv_idx')
+            )
+            FROM pg_inherits p
+            JOIN pg_class c ON c.oid = p.inhrelid
+            WHERE p.inhparent = 'chanlog'::regclass",
         &[],
     )?;
 
-    db.execute(
-        "CREATE TABLE IF NOT EXISTS chanlog (
-            id SERIAL PRIMARY KEY,
-            command VARCHAR,
-            target VARCHAR,
-            user_id INT,
-            user_type VARCHAR,
-            username VARCHAR,
-            sub_count INT,
-            system_msg VARCHAR,
-            user_msg VARCHAR,
-            timestamp TIMESTAMP WITH TIME ZONE
+    for row in partitions {
+        let (partition, attached, valid): (String, bool, Option<bool>) =
+            (row.get(0), row.get(1), row.get(2));
+        let index = format!("{}_user_msg_tsv_idx", partition);
+
+        if attached {
+            continue;
+        }
+
+        if valid == Some(false) {
+            db.batch_execute(&format!("DROP INDEX CONCURRENTLY {}", index))?;
+        }
+
+        if valid != Some(true) {
+            db.batch_execute(&format!(
+                "CREATE INDEX CONCURRENTLY {} ON {} USING GIN ({})",
+                index, partition, SEARCH_EXPRESSION
+            ))?;
+        }
+
+        db.batch_execute(&format!(
+            "ALTER INDEX chanlog_user_msg_tsv_idx ATTACH PARTITION {}",
+            index
+        ))?;
+    }
+
+    Ok(())
+}
+
+fn apply_migrations(db: &mut postgres::Client) -> Result<(), error::Error> {
+    db.batch_execute(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version INT PRIMARY KEY,
+            applied_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now()
         );",
-        &[],
     )?;
 
+    let current: i32 =
+        db.query_one("SELECT COALESCE(MAX(version), 0) FROM schema_version", &[])?.get(0);
+
+    for (version, sql) in MIGRATIONS.iter().filter(|(version, _)| *version > current) {
+        let mut transaction = db.transaction()?;
+
+        transaction.batch_execute(sql)?;
+        transaction.execute("INSERT INTO schema_version (version) VALUES ($1)", &[version])?;
+        transaction.commit()?;
+
+        println!("Applied database migration {}...", version);
+    }
+
     Ok(())
 }
 
-pub fn insert_admin_log(log: message::Message) -> Result<(), error::Error> {
-    thread::spawn(move || -> Result<(), error::Error> {
-        let mut db = connect()?;
+const fn next_month(year: i32, month: u32) -> (i32, u32) {
+    if month == 12 {
+        (year + 1, 1)
+    } else {
+        (year, month + 1)
+    }
+}
+
+// Creates the partitions for the current and the following month, so the rollover at the end
+// of a month never lands in `chanlog_default`.
+fn ensure_partitions(pool: &Pool) -> Result<(), error::Error> {
+    let mut db = pool.get()?;
+    let now = Utc::now();
+    let current = (now.year(), now.month());
+
+    for &(year, month) in &[current, next_month(current.0, current.1)] {
+        let (end_year, end_month) = next_month(year, month);
+
+        db.batch_execute(&format!(
+            "CREATE TABLE IF NOT EXISTS chanlog_{}_{:02} PARTITION OF chanlog
+                FOR VALUES FROM ('{}-{:02}-01 00:00:00+00') TO ('{}-{:02}-01 00:00:00+00');",
+            year, month, year, month, end_year, end_month
+        ))?;
+    }
 
-        db.execute(
+    Ok(())
+}
+
+fn write_admin_logs(pool: &Pool, logs: &[message::Message]) -> Result<(), error::Error> {
+    let mut db = pool.get()?;
+    let mut transaction = db.transaction()?;
+
+    for log in logs {
+        transaction.execute(
             "INSERT INTO adminlog (user_id, username, user_msg, timestamp) VALUES ($1, $2, $3, $4)",
             &[&log.user_id, &log.username, &log.user_msg, &log.timestamp],
         )?;
+    }
 
-        Ok(())
-    });
+    transaction.commit()?;
 
     Ok(())
 }
 
-pub fn insert_logs(logs: std::vec::Vec<message::Message>) -> Result<(), error::Error> {
-    thread::spawn(move || -> Result<(), error::Error> {
-        let mut db = connect()?;
-        let mut transaction = db.transaction()?;
+const CHANLOG_COLUMNS: &str = "msg_id, command, target, room_id, user_id, user_type, username, sub_count, system_msg, user_msg, timestamp";
+const CHANLOG_TYPES: [Type; 11] = [
+    Type::VARCHAR,
+    Type::VARCHAR,
+    Type::VARCHAR,
+    Type::INT4,
+    Type::INT4,
+    Type::VARCHAR,
+    Type::VARCHAR,
+    Type::INT4,
+    Type::VARCHAR,
+    Type::VARCHAR,
+    Type::TIMESTAMPTZ,
+];
 
-        for log in &logs {
-            let user_type = log.user_type.to_string();
+// Upserts every channel and user referenced by the batch, keeping the most recently seen name,
+// and records each name a user was seen with. Each table takes one statement over arrays of
+// the deduped rows, ordered by id so concurrent writers lock rows in the same order.
+fn upsert_entities(
+    transaction: &mut postgres::Transaction,
+    logs: &[message::Message],
+) -> Result<(), error::Error> {
+    let entities = store::entities(logs);
 
-            transaction.execute(
-                "INSERT INTO chanlog (command, target, user_id, user_type, username, sub_count, system_msg, user_msg, timestamp) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
-                &[&log.command, &log.target, &log.user_id, &user_type, &log.username, &log.sub_count, &log.system_msg, &log.user_msg, &log.timestamp],
-            )?;
-        }
+    for (table, key, name, entities) in &[
+        ("channels", "room_id", "name", &entities.channels),
+        ("users", "user_id", "username", &entities.users),
+    ] {
+        let ids: Vec<i32> = entities.keys().copied().collect();
+        let names: Vec<&str> = entities.values().map(|x| x.0).collect();
+        let first_seen: Vec<DateTime<Utc>> = entities.values().map(|x| x.1).collect();
+        let last_seen: Vec<DateTime<Utc>> = entities.values().map(|x| x.2).collect();
 
-        transaction.commit()?;
+        transaction.execute(
+            format!(
+                "INSERT INTO {table} ({key}, {name}, first_seen, last_seen)
+                    SELECT * FROM UNNEST($1::INT[], $2::VARCHAR[], $3::TIMESTAMPTZ[],
+                        $4::TIMESTAMPTZ[]) ORDER BY 1
+                    ON CONFLICT ({key}) DO UPDATE SET
+                        {name} = CASE WHEN EXCLUDED.last_seen >= {table}.last_seen
+                            THEN EXCLUDED.{name} ELSE {table}.{name} END,
+                        first_seen = LEAST({table}.first_seen, EXCLUDED.first_seen),
+                        last_seen = GREATEST({table}.last_seen, EXCLUDED.last_seen)",
+                table = table,
+                key = key,
+                name = name
+            )
+            .as_str(),
+            &[&ids, &names, &first_seen, &last_seen],
+        )?;
+    }
 
-        db.close()?;
+    let ids: Vec<i32> = entities.names.keys().map(|x| x.0).collect();
+    let logins: Vec<&str> = entities.names.keys().map(|x| x.1).collect();
+    let display_names: Vec<&str> = entities.names.keys().map(|x| x.2).collect();
+    let first_seen: Vec<DateTime<Utc>> = entities.names.values().map(|x| x.0).collect();
+    let last_seen: Vec<DateTime<Utc>> = entities.names.values().map(|x| x.1).collect();
 
-        Ok(())
-    });
+    transaction.execute(
+        "INSERT INTO user_names (user_id, login, display_name, first_seen, last_seen)
+            SELECT * FROM UNNEST($1::INT[], $2::VARCHAR[], $3::VARCHAR[], $4::TIMESTAMPTZ[],
+                $5::TIMESTAMPTZ[]) ORDER BY 1, 2, 3
+            ON CONFLICT (user_id, login, display_name) DO UPDATE SET
+                first_seen = LEAST(user_names.first_seen, EXCLUDED.first_seen),
+                last_seen = GREATEST(user_names.last_seen, EXCLUDED.last_seen)",
+        &[&ids, &logins, &display_names, &first_seen, &last_seen],
+    )?;
 
     Ok(())
 }
+
+// Every name recorded for the user id(s) that have ever used `login`, oldest first.
+fn aliases(pool: &Pool, login: &str) -> Result<Vec<store::Alias>, error::Error> {
+    let mut db = pool.get()?;
+    let rows = db.query(
+        "SELECT login, display_name, first_seen, last_seen FROM user_names
+            WHERE user_id IN (SELECT user_id FROM user_names WHERE login = $1)
+            ORDER BY first_seen",
+        &[&login],
+    )?;
+
+    Ok(rows
+        .iter()
+        .map(|row| store::Alias {
+            login: row.get(0),
+            display_name: row.get(1),
+            first_seen: row.get(2),
+            last_seen: row.get(3),
+        })
+        .collect())
+}
+
+const HIT_COLUMNS: &str = "COALESCE(msg_id, ''), target, username, COALESCE(system_msg, ''),
+    COALESCE(user_msg, ''), \"timestamp\"";
+
+fn hit(row: &postgres::Row) -> search::Hit {
+    search::Hit {
+        msg_id: row.get(0),
+        target: row.get(1),
+        username: row.get(2),
+        system_msg: row.get(3),
+        user_msg: row.get(4),
+        timestamp: row.get(5),
+    }
+}
+
+// Newest matches first. `websearch_to_tsquery` accepts the same syntax as web search boxes:
+// "quoted phrase", `or` and `-excluded`.
+fn search(
+    pool: &Pool,
+    query: &search::Query,
+    limit: u32,
+) -> Result<Vec<search::Hit>, error::Error> {
+    let mut db = pool.get()?;
+    let rows = db.query(
+        format!(
+            "SELECT {} FROM chanlog
+                WHERE ($1 = '' OR {} @@ websearch_to_tsquery('simple', $1))
+                    AND ($2::VARCHAR IS NULL OR target = $2)
+                    AND ($3::VARCHAR IS NULL OR username = $3)
+                    AND ($4::TIMESTAMPTZ IS NULL OR \"timestamp\" >= $4)
+                    AND ($5::TIMESTAMPTZ IS NULL OR \"timestamp\" < $5)
+                ORDER BY \"timestamp\" DESC
+                LIMIT $6",
+            HIT_COLUMNS, SEARCH_EXPRESSION
+        )
+        .as_str(),
+        &[&query.text, &query.channel, &query.user, &query.since, &query.until, &i64::from(limit)],
+    )?;
+
+    Ok(rows.iter().map(hit).collect())
+}
+
+fn days(pool: &Pool, channel: &str, user: Option<&str>) -> Result<Vec<NaiveDate>, error::Error> {
+    let mut db = pool.get()?;
+    let rows = db.query(
+        "SELECT DISTINCT (\"timestamp\" AT TIME ZONE 'UTC')::DATE AS day FROM chanlog
+            WHERE target = $1 AND ($2::VARCHAR IS NULL OR username = $2)
+            ORDER BY day DESC",
+        &[&channel, &user],
+    )?;
+
+    Ok(rows.iter().map(|row| row.get(0)).collect())
+}
+
+// Channel names come from `channels`, so channels only logged before it existed are left out.
+fn channels(pool: &Pool) -> Result<Vec<String>, error::Error> {
+    let mut db = pool.get()?;
+    let rows = db.query("SELECT name FROM channels ORDER BY name", &[])?;
+
+    Ok(rows.iter().map(|row| row.get(0)).collect())
+}
+
+fn logs(
+    pool: &Pool,
+    channel: &str,
+    since: DateTime<Utc>,
+    until: DateTime<Utc>,
+) -> Result<Vec<search::Hit>, error::Error> {
+    let mut db = pool.get()?;
+    let rows = db.query(
+        format!(
+            "SELECT {} FROM chanlog
+                WHERE target = $1 AND \"timestamp\" >= $2 AND \"timestamp\" < $3
+                ORDER BY \"timestamp\"",
+            HIT_COLUMNS
+        )
+        .as_str(),
+        &[&channel, &since, &until],
+    )?;
+
+    Ok(rows.iter().map(hit).collect())
+}
+
+fn moderation(
+    pool: &Pool,
+    channel: &str,
+    since: DateTime<Utc>,
+    until: DateTime<Utc>,
+) -> Result<Vec<search::Moderation>, error::Error> {
+    let mut db = pool.get()?;
+    let rows = db.query(
+        "SELECT command, username, COALESCE(target_msg_id, ''), ban_duration, user_msg, \"timestamp\"
+            FROM moderation
+            WHERE target = $1 AND \"timestamp\" >= $2 AND \"timestamp\" < $3
+            ORDER BY \"timestamp\"",
+        &[&channel, &since, &until],
+    )?;
+
+    Ok(rows
+        .iter()
+        .map(|row| search::Moderation {
+            command: row.get(0),
+            username: row.get(1),
+            target_msg_id: row.get(2),
+            ban_duration: row.get(3),
+            user_msg: row.get(4),
+            timestamp: row.get(5),
+        })
+        .collect())
+}
+
+fn insert_moderation(
+    transaction: &mut postgres::Transaction,
+    logs: &[message::Message],
+) -> Result<(), error::Error> {
+    let statement = transaction.prepare(
+        "INSERT INTO moderation (command, target, room_id, user_id, username, target_msg_id,
+            ban_duration, user_msg, \"timestamp\") VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+            ON CONFLICT DO NOTHING",
+    )?;
+
+    for log in logs {
+        transaction.execute(
+            &statement,
+            &[
+                &log.command,
+                &log.target,
+                &store::room_id(log),
+                &store::user_id(log),
+                &log.username,
+                &store::target_msg_id(log),
+                &log.ban_duration,
+                &log.user_msg,
+                &log.timestamp,
+            ],
+        )?;
+    }
+
+    Ok(())
+}
+
+// COPY cannot skip conflicting rows, so batches are copied into a temporary staging table and
+// moved over with `ON CONFLICT DO NOTHING`, which drops messages that were already logged.
+fn copy_rows(
+    transaction: &mut postgres::Transaction,
+    table: &str,
+    logs: &[message::Message],
+) -> Result<(), error::Error> {
+    transaction.batch_execute(&format!(
+        "CREATE TEMP TABLE IF NOT EXISTS chanlog_staging ON COMMIT DELETE ROWS AS
+            SELECT {} FROM chanlog WITH NO DATA",
+        CHANLOG_COLUMNS
+    ))?;
+
+    let sink = transaction
+        .copy_in(&format!("COPY chanlog_staging ({}) FROM STDIN BINARY", CHANLOG_COLUMNS))?;
+    let mut writer = BinaryCopyInWriter::new(sink, &CHANLOG_TYPES);
+
+    for log in logs {
+        let user_type = log.user_type.to_string();
+        let msg_id = store::msg_id(log);
+        let room_id = store::room_id(log);
+
+        writer.write(&[
+            &msg_id,
+            &log.command,
+            &log.target,
+            &room_id,
+            &log.user_id,
+            &user_type,
+            &log.username,
+            &log.sub_count,
+            &log.system_msg,
+            &log.user_msg,
+            &log.timestamp,
+        ])?;
+    }
+
+    writer.finish()?;
+
+    transaction.batch_execute(&format!(
+        "INSERT INTO {table} ({columns}) SELECT {columns} FROM chanlog_staging
+            ON CONFLICT DO NOTHING",
+        table = table,
+        columns = CHANLOG_COLUMNS
+    ))?;
+
+    Ok(())
+}
+
+// The per-row path is kept so `bench` can compare it against COPY.
+fn insert_rows(
+    transaction: &mut postgres::Transaction,
+    table: &str,
+    logs: &[message::Message],
+) -> Result<(), error::Error> {
+    let statement = transaction.prepare(&format!(
+        "INSERT INTO {} ({}) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+            ON CONFLICT DO NOTHING",
+        table, CHANLOG_COLUMNS
+    ))?;
+
+    for log in logs {
+        let user_type = log.user_type.to_string();
+        let msg_id = store::msg_id(log);
+        let room_id = store::room_id(log);
+
+        transaction.execute(
+            &statement,
+            &[
+                &msg_id,
+                &log.command,
+                &log.target,
+                &room_id,
+                &log.user_id,
+                &user_type,
+                &log.username,
+                &log.sub_count,
+                &log.system_msg,
+                &log.user_msg,
+                &log.timestamp,
+            ],
+        )?;
+    }
+
+    Ok(())
+}
+
+fn write_logs(pool: &Pool, logs: &[message::Message]) -> Result<(), error::Error> {
+    let (logs, moderation) = store::split(logs);
+    let mut db = pool.get()?;
+    let mut transaction = db.transaction()?;
+
+    upsert_entities(&mut transaction, &logs)?;
+    copy_rows(&mut transaction, "chanlog", &logs)?;
+    insert_moderation(&mut transaction, &moderation)?;
+
+    transaction.commit()?;
+
+    Ok(())
+}
+
+// Writes the same synthetic batch through both paths into a temporary copy of chanlog and
+// returns how long each took.
+pub fn bench(pool: &Pool, rows: usize) -> Result<(time::Duration, time::Duration), error::Error> {
+    let mut db = pool.get()?;
+    let logs: Vec<message::Message> = (0..rows)
+        .map(|i| message::Message {
+            msg_id: format!("bench-{}", i),
+            command: String::from("PRIVMSG"),
+            target: String::from("#bench"),
+            room_id: 1,
+            user_id: i32::try_from(i % 1000).unwrap_or(0),
+            user_type: message::UserType::User,
+            username: format!("user{}", i % 1000),
+            display_name: format!("User{}", i % 1000),
+            sub_count: 0,
+            system_msg: String::from(""),
+            user_msg: format!("benchmark message number {}", i),
+            timestamp: Utc::now(),
+            target_msg_id: String::from(""),
+            ban_duration: 0,
+        })
+        .collect();
+
+    db.batch_execute(
+        "CREATE TEMP TABLE chanlog_bench (LIKE chanlog INCLUDING DEFAULTS INCLUDING INDEXES)",
+    )?;
+
+    let start = time::Instant::now();
+    let mut transaction = db.transaction()?;
+    insert_rows(&mut transaction, "chanlog_bench", &logs)?;
+    transaction.commit()?;
+    let insert = start.elapsed();
+
+    db.batch_execute("TRUNCATE chanlog_bench")?;
+
+    let start = time::Instant::now();
+    let mut transaction = db.transaction()?;
+    copy_rows(&mut transaction, "chanlog_bench", &logs)?;
+    transaction.commit()?;
+    let copy = start.elapsed();
+
+    db.batch_execute("DROP TABLE chanlog_bench")?;
+
+    Ok((insert, copy))
+}
+
+#[derive(Debug)]
+pub struct Postgres {
+    pub pool: Pool,
+    partitioned: AtomicU32,
+}
+
+impl Postgres {
+    pub fn new(url: &str) -> Result<Self, error::Error> {
+        Ok(Self { pool: pool(url)?, partitioned: AtomicU32::new(Utc::now().month()) })
+    }
+}
+
+impl store::Store for Postgres {
+    fn migrate(&self) -> Result<(), error::Error> {
+        migrate(&self.pool)
+    }
+
+    fn write_logs(&self, logs: &[message::Message]) -> Result<(), error::Error> {
+        let month = Utc::now().month();
+
+        if self.partitioned.load(Ordering::Relaxed) != month {
+            match ensure_partitions(&self.pool) {
+                Ok(_) => self.partitioned.store(month, Ordering::Relaxed),
+                Err(e) => eprintln!("Unable to create chanlog partitions: {}", e),
+            }
+        }
+
+        write_logs(&self.pool, logs)
+    }
+
+    fn write_admin_logs(&self, logs: &[message::Message]) -> Result<(), error::Error> {
+        write_admin_logs(&self.pool, logs)
+    }
+
+    fn aliases(&self, login: &str) -> Result<Vec<store::Alias>, error::Error> {
+        aliases(&self.pool, login)
+    }
+
+    fn search(&self, query: &search::Query, limit: u32) -> Result<Vec<search::Hit>, error::Error> {
+        search(&self.pool, query, limit)
+    }
+
+    fn channels(&self) -> Result<Vec<String>, error::Error> {
+        channels(&self.pool)
+    }
+
+    fn logs(
+        &self,
+        channel: &str,
+        since: DateTime<Utc>,
+        until: DateTime<Utc>,
+    ) -> Result<Vec<search::Hit>, error::Error> {
+        logs(&self.pool, channel, since, until)
+    }
+
+    fn moderation(
+        &self,
+        channel: &str,
+        since: DateTime<Utc>,
+        until: DateTime<Utc>,
+    ) -> Result<Vec<search::Moderation>, error::Error> {
+        moderation(&self.pool, channel, since, until)
+    }
+
+    fn days(&self, channel: &str, user: Option<&str>) -> Result<Vec<NaiveDate>, error::Error> {
+        days(&self.pool, channel, user)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Migrations run against the database in `TEST_POSTGRES`, inside a schema of their own;
+    // without it, they are skipped.
+    fn connect(schema: &str) -> Option<postgres::Client> {
+        let url = std::env::var("TEST_POSTGRES").ok()?;
+        let mut db = postgres::Client::connect(&url, NoTls).unwrap();
+
+        db.batch_execute(&format!(
+            "DROP SCHEMA IF EXISTS {0} CASCADE; CREATE SCHEMA {0}; SET search_path TO {0};",
+            schema
+        ))
+        .unwrap();
+
+        Some(db)
+    }
+
+    #[test]
+    fn partitions_rows_from_this_month() {
+        let Some(mut db) = connect("migrate_test") else {
+            return;
+        };
+
+        db.batch_execute(MIGRATIONS[0].1).unwrap();
+        db.batch_execute(
+            "CREATE TABLE schema_version (
+                version INT PRIMARY KEY,
+                applied_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now()
+            );
+            INSERT INTO schema_version (version) VALUES (1);
+            INSERT INTO chanlog (command, target, user_id, username, user_msg, timestamp) VALUES
+                ('PRIVMSG', '#chan', 1, 'old', 'a', now() - interval '1 year'),
+                ('PRIVMSG', '#chan', 1, 'new', 'b', now());",
+        )
+        .unwrap();
+
+        apply_migrations(&mut db).unwrap();
+
+        let now = Utc::now();
+        let rows: Vec<(String, String)> = db
+            .query("SELECT tableoid::regclass::text, username FROM chanlog ORDER BY id", &[])
+            .unwrap()
+            .iter()
+            .map(|x| (x.get(0), x.get(1)))
+            .collect();
+
+        assert_eq!(
+            rows,
+            vec![
+                (String::from("chanlog_legacy"), String::from("old")),
+                (format!("chanlog_{}_{:02}", now.year(), now.month()), String::from("new")),
+            ]
+        );
+
+        db.batch_execute("DROP SCHEMA migrate_test CASCADE").unwrap();
+    }
+}
@@ -5,8 +5,14 @@ pub enum Error {
     Io(std::io::Error),
     Json(serde_json::Error),
     Postgres(postgres::Error),
+    Pool(r2d2::Error),
+    Sqlite(rusqlite::Error),
     Regex(regex::Error),
-    Irc(irc::error::IrcError),
+    Index(tantivy::TantivyError),
+    Irc(String),
+    Tls(tokio_native_tls::native_tls::Error),
+    Http(Box<dyn std::error::Error + Send + Sync>),
+    Config(String),
 }
 
 impl fmt::Display for Error {
@@ -15,8 +21,13 @@ impl fmt::Display for Error {
             Self::Io(ref err) => write!(f, "{}", err),
             Self::Json(ref err) => write!(f, "{}", err),
             Self::Postgres(ref err) => write!(f, "{}", err),
+            Self::Pool(ref err) => write!(f, "{}", err),
+            Self::Sqlite(ref err) => write!(f, "{}", err),
             Self::Regex(ref err) => write!(f, "{}", err),
-            Self::Irc(ref err) => write!(f, "{}", err),
+            Self::Index(ref err) => write!(f, "{}", err),
+            Self::Irc(ref err) | Self::Config(ref err) => write!(f, "{}", err),
+            Self::Tls(ref err) => write!(f, "{}", err),
+            Self::Http(ref err) => write!(f, "{}", err),
         }
     }
 }
@@ -39,14 +50,38 @@ impl From<postgres::Error> for Error {
     }
 }
 
+impl From<r2d2::Error> for Error {
+    fn from(err: r2d2::Error) -> Self {
+        Self::Pool(err)
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(err: rusqlite::Error) -> Self {
+        Self::Sqlite(err)
+    }
+}
+
 impl From<regex::Error> for Error {
     fn from(err: regex::Error) -> Self {
         Self::Regex(err)
     }
 }
 
-impl From<irc::error::IrcError> for Error {
-    fn from(err: irc::error::IrcError) -> Self {
-        Self::Irc(err)
+impl From<tantivy::TantivyError> for Error {
+    fn from(err: tantivy::TantivyError) -> Self {
+        Self::Index(err)
+    }
+}
+
+impl From<tantivy::query::QueryParserError> for Error {
+    fn from(err: tantivy::query::QueryParserError) -> Self {
+        Self::Index(err.into())
+    }
+}
+
+impl From<tokio_native_tls::native_tls::Error> for Error {
+    fn from(err: tokio_native_tls::native_tls::Error) -> Self {
+        Self::Tls(err)
     }
 }
@@ -1,8 +1,9 @@
+use chrono::prelude::*;
 use std::fs;
-use std::io::{BufWriter, Write};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
 use std::path;
 
-use crate::lib::{error, message};
+use crate::lib::{config, error, message, search};
 
 #[derive(Debug)]
 pub struct Logger {
@@ -31,55 +32,214 @@ impl Logger {
         Ok(())
     }
 
-    pub fn save_msg_txt(parsed_msg: &message::Message, buffer: usize) -> Result<(), error::Error> {
-        let path = format!(
-            "logs/{}/{}.txt",
-            parsed_msg.target.replace("#", ""),
-            parsed_msg.timestamp.format("%Y-%m-%d")
-        );
+    // Returns the file and byte offset the message was written at.
+    pub fn save_msg_txt(
+        parsed_msg: &message::Message,
+        buffer: usize,
+        templates: &config::Templates,
+    ) -> Result<(String, u64), error::Error> {
+        let path = log_path(&parsed_msg.target, parsed_msg.timestamp.naive_utc().date())?;
         let logger = Self::new(&path)?;
+        let offset = logger.file.metadata()?.len();
         let mut file = BufWriter::with_capacity(buffer, logger.file);
 
         if parsed_msg.system_msg.is_empty() {
-            let moderator = match parsed_msg.user_type {
-                message::UserType::Moderator => format!("[{}]", parsed_msg.user_type),
-                _ => "".to_string(),
-            };
-
-            file.write_fmt(format_args!(
-                "{} {}[{}] {}: {}\n",
-                parsed_msg.timestamp.format("%Y-%m-%d %H:%M:%S"),
-                moderator,
-                parsed_msg.sub_count,
-                parsed_msg.username,
-                parsed_msg.user_msg
-            ))?;
-        } else if !parsed_msg.system_msg.is_empty() && !parsed_msg.user_msg.is_empty() {
-            file.write_fmt(format_args!(
-                "{} - [Notice] {}\n{} - [Subscription Message] {}\n",
-                parsed_msg.timestamp.format("%Y-%m-%d %H:%M:%S"),
-                parsed_msg.system_msg,
-                parsed_msg.timestamp.format("%Y-%m-%d %H:%M:%S"),
-                parsed_msg.user_msg
-            ))?;
+            writeln!(file, "{}", render(&templates.message, parsed_msg, &templates.timestamp))?;
+        } else if !parsed_msg.user_msg.is_empty() && !parsed_msg.is_moderation() {
+            writeln!(file, "{}", render(&templates.notice, parsed_msg, &templates.timestamp))?;
+            writeln!(
+                file,
+                "{}",
+                render(&templates.subscription, parsed_msg, &templates.timestamp)
+            )?;
         } else {
-            file.write_fmt(format_args!(
-                "{} - [Notice] {}\n",
-                parsed_msg.timestamp.format("%Y-%m-%d %H:%M:%S"),
-                parsed_msg.system_msg,
-            ))?;
+            writeln!(file, "{}", render(&templates.notice, parsed_msg, &templates.timestamp))?;
         }
 
-        Ok(())
+        Ok((path, offset))
+    }
+}
+
+// Placeholders are replaced in one pass, so field values are never expanded again, and unknown
+// ones are left in the line as written.
+fn render(template: &str, parsed_msg: &message::Message, timestamp: &str) -> String {
+    let badges = match parsed_msg.user_type {
+        message::UserType::Moderator => format!("[{}]", parsed_msg.user_type),
+        _ => "".to_string(),
+    };
+
+    let fields = [
+        ("timestamp", parsed_msg.timestamp.format(timestamp).to_string()),
+        ("channel", parsed_msg.target.clone()),
+        ("user_id", parsed_msg.user_id.to_string()),
+        ("user", parsed_msg.username.clone()),
+        ("badges", badges),
+        ("sub_months", parsed_msg.sub_count.to_string()),
+        ("system_msg", parsed_msg.system_msg.clone()),
+        ("message", parsed_msg.user_msg.clone()),
+    ];
+
+    let mut line = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        line.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let field = rest.find('}').and_then(|end| {
+            fields.iter().find(|(key, _)| *key == &rest[1..end]).map(|(_, value)| (end, value))
+        });
+
+        if let Some((end, value)) = field {
+            line.push_str(value);
+            rest = &rest[end + 1..];
+        } else {
+            line.push('{');
+            rest = &rest[1..];
+        }
+    }
+
+    line.push_str(rest);
+    line
+}
+
+// Channel names become directories under `logs`, so only Twitch logins are accepted, and not
+// the directories the bot keeps for itself.
+pub fn valid_channel(channel: &str) -> bool {
+    let name = channel.trim_start_matches('#');
+
+    (1..=25).contains(&name.len())
+        && name.bytes().all(|x| x.is_ascii_lowercase() || x.is_ascii_digit() || x == b'_')
+        && name != "admin"
+        && name != "index"
+}
+
+fn channel_dir(channel: &str) -> Result<String, error::Error> {
+    if !valid_channel(channel) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid channel name: {}", channel),
+        )
+        .into());
     }
+
+    Ok(format!("logs/{}", channel.trim_start_matches('#')))
+}
+
+pub fn log_path(channel: &str, day: NaiveDate) -> Result<String, error::Error> {
+    Ok(format!("{}/{}.txt", channel_dir(channel)?, day.format("%Y-%m-%d")))
+}
+
+fn moderation_path(channel: &str, day: NaiveDate) -> Result<String, error::Error> {
+    Ok(format!("{}/{}.moderation.jsonl", channel_dir(channel)?, day.format("%Y-%m-%d")))
+}
+
+// Moderation events are also kept as JSON lines next to the day's text log, so deleted and
+// timed-out messages can be told apart without parsing rendered lines.
+pub fn save_moderation(parsed_msg: &message::Message) -> Result<(), error::Error> {
+    let path = moderation_path(&parsed_msg.target, parsed_msg.timestamp.naive_utc().date())?;
+    let logger = Logger::new(&path)?;
+    let mut line = serde_json::to_vec(&search::Moderation::from(parsed_msg))?;
+
+    line.push(b'\n');
+    BufWriter::new(logger.file).write_all(&line)?;
+
+    Ok(())
+}
+
+pub fn moderation(channel: &str, day: NaiveDate) -> Result<Vec<search::Moderation>, error::Error> {
+    let file = match fs::File::open(moderation_path(channel, day)?) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    Ok(BufReader::new(file)
+        .lines()
+        .filter_map(|x| x.ok().and_then(|line| serde_json::from_str(&line).ok()))
+        .collect())
+}
+
+// Days with a text log for `channel`, newest first.
+pub fn days(channel: &str) -> Result<Vec<NaiveDate>, error::Error> {
+    let entries = match fs::read_dir(channel_dir(channel)?) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut days = Vec::new();
+
+    for entry in entries {
+        let name = entry?.file_name().to_string_lossy().to_string();
+
+        if let Some(day) =
+            name.strip_suffix(".txt").and_then(|x| NaiveDate::parse_from_str(x, "%Y-%m-%d").ok())
+        {
+            days.push(day);
+        }
+    }
+
+    days.sort_unstable_by(|a, b| b.cmp(a));
+
+    Ok(days)
+}
+
+// Every directory under `logs` that holds channel logs.
+pub fn channels() -> Result<Vec<String>, error::Error> {
+    let mut channels = Vec::new();
+
+    for entry in fs::read_dir("logs")? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+
+        if entry.file_type()?.is_dir() && name != "admin" && name != "index" {
+            channels.push(name);
+        }
+    }
+
+    channels.sort();
+
+    Ok(channels)
 }
 
 pub fn create_dirs(channels: &[String]) -> Result<(), error::Error> {
     fs::create_dir_all(path::Path::new("logs/admin/"))?;
 
     for channel in channels.iter() {
-        fs::create_dir_all(path::Path::new("logs/").join(channel.replace("#", "")))?;
+        fs::create_dir_all(channel_dir(channel)?)?;
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lib::twitch;
+
+    #[test]
+    fn renders_placeholders_once() {
+        let line = "@badge-info=;badges=;color=;display-name=Bob;emotes=;flags=;id=m-1;mod=0;\
+            room-id=5;subscriber=0;tmi-sent-ts=1791626400000;turbo=0;user-id=7;user-type= \
+            :bob!bob@bob.tmi.twitch.tv PRIVMSG #chan :{user} said {timestamp}";
+        let raw_msg: twitch::Message = line.parse().unwrap();
+        let parsed_msg = message::Message::parse_msg(&raw_msg).unwrap();
+
+        assert_eq!(
+            render("{channel} {user}: {message} {unknown}", &parsed_msg, "%Y"),
+            "#chan bob: {user} said {timestamp} {unknown}"
+        );
+    }
+
+    #[test]
+    fn rejects_paths_outside_channel_logs() {
+        assert!(valid_channel("#dansgaming"));
+        assert!(valid_channel("some_user_42"));
+
+        for channel in ["", "#..", "../config.json", "a/b", "#Caps", "admin", "#index"] {
+            assert!(!valid_channel(channel), "{}", channel);
+        }
+
+        assert!(log_path("#..", NaiveDate::from_ymd(2021, 11, 1)).is_err());
+    }
+}
@@ -0,0 +1,120 @@
+use chrono::prelude::*;
+use std::sync::atomic::{AtomicI64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use crate::lib::{error, http, metrics, store};
+
+// Twitch sends a PING about every five minutes, so a quiet connection is stale after two.
+const STALE_SECONDS: i64 = 10 * 60;
+
+static CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+static CONNECTIONS_UP: AtomicUsize = AtomicUsize::new(0);
+static STARTED: AtomicI64 = AtomicI64::new(0);
+static LAST_MESSAGE: AtomicI64 = AtomicI64::new(0);
+
+lazy_static! {
+    // The error of the last failed database write, cleared by the next successful one.
+    static ref DATABASE_ERROR: Mutex<Option<String>> = Mutex::new(None);
+}
+
+#[derive(Serialize, Debug)]
+struct Database {
+    enabled: bool,
+    healthy: bool,
+    error: Option<String>,
+    pending_rows: usize,
+    spilled_batches: usize,
+}
+
+#[derive(Serialize, Debug)]
+struct Connections {
+    open: usize,
+    up: usize,
+}
+
+#[derive(Serialize, Debug)]
+struct Report {
+    status: &'static str,
+    connected: bool,
+    connections: Connections,
+    last_message: Option<DateTime<Utc>>,
+    paused: bool,
+    database: Database,
+}
+
+pub fn start() {
+    STARTED.store(Utc::now().timestamp(), Ordering::Relaxed);
+}
+
+// Any line from Twitch, including PINGs, shows a connection is alive.
+pub fn message() {
+    LAST_MESSAGE.store(Utc::now().timestamp(), Ordering::Relaxed);
+}
+
+// Connections that dropped or were asked to reconnect are down until they are replaced.
+pub fn connections(up: usize, open: usize) {
+    CONNECTIONS_UP.store(up, Ordering::Relaxed);
+    CONNECTIONS.store(open, Ordering::Relaxed);
+}
+
+// Every connection is up, once there is at least one.
+fn connected() -> bool {
+    let open = CONNECTIONS.load(Ordering::Relaxed);
+
+    open > 0 && CONNECTIONS_UP.load(Ordering::Relaxed) == open
+}
+
+pub fn database(result: Result<(), &error::Error>) {
+    *DATABASE_ERROR.lock().expect("Unable to acquire health mutex") =
+        result.err().map(ToString::to_string);
+}
+
+fn report(ok: bool) -> Result<http::Reply, error::Error> {
+    let (enabled, paused) = metrics::state().map_or((false, false), |x| (x.database, x.paused));
+    let error = if enabled {
+        DATABASE_ERROR.lock().expect("Unable to acquire health mutex").clone()
+    } else {
+        None
+    };
+    let last_message = LAST_MESSAGE.load(Ordering::Relaxed);
+
+    let mut reply = http::Reply::json(&Report {
+        status: if ok { "ok" } else { "unavailable" },
+        connected: connected(),
+        connections: Connections {
+            open: CONNECTIONS.load(Ordering::Relaxed),
+            up: CONNECTIONS_UP.load(Ordering::Relaxed),
+        },
+        last_message: Some(last_message).filter(|x| *x > 0).map(|x| Utc.timestamp(x, 0)),
+        paused,
+        database: Database {
+            enabled,
+            healthy: error.is_none(),
+            error,
+            pending_rows: store::PENDING.load(Ordering::Relaxed),
+            spilled_batches: store::SPILLED.load(Ordering::Relaxed),
+        },
+    })?;
+
+    reply.status = if ok { 200 } else { 503 };
+
+    Ok(reply)
+}
+
+// Liveness: fails once nothing has arrived from Twitch for `STALE_SECONDS`, counting from
+// startup, so a supervisor restarts a bot that is stuck connecting or on a dead connection.
+pub fn live() -> Result<http::Reply, error::Error> {
+    let active = LAST_MESSAGE.load(Ordering::Relaxed).max(STARTED.load(Ordering::Relaxed));
+
+    report(Utc::now().timestamp() - active < STALE_SECONDS)
+}
+
+// Readiness: every connection to Twitch is up, logging is not paused, and the last database
+// write, if a database is enabled, succeeded.
+pub fn ready() -> Result<http::Reply, error::Error> {
+    // Not ready until the first connection has set up its state.
+    let (running, database) = metrics::state().map_or((false, false), |x| (!x.paused, x.database));
+    let healthy = DATABASE_ERROR.lock().expect("Unable to acquire health mutex").is_none();
+
+    report(connected() && running && (!database || healthy))
+}
@@ -0,0 +1,362 @@
+use chrono::prelude::*;
+use percent_encoding::percent_decode_str;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::{fs, io, thread};
+
+use crate::lib::{error, file, health, index, justlog, metrics, search, store, viewer};
+
+const WORKERS: usize = 4;
+const DEFAULT_LIMIT: u32 = 100;
+const MAX_LIMIT: u32 = 1000;
+// Upper bound on index matches returned for one day of a channel when reading from text logs.
+const DAY_LIMIT: u32 = 100_000;
+
+// Where the API reads from: the configured database, or the text logs and their index.
+#[derive(Clone)]
+pub enum Source {
+    Store(Arc<dyn store::Store>),
+    Files,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Format {
+    Json,
+    Text,
+}
+
+pub struct Reply {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Reply {
+    pub fn json<T: serde::Serialize>(value: &T) -> Result<Self, error::Error> {
+        Ok(Self {
+            status: 200,
+            content_type: "application/json",
+            body: serde_json::to_string(value)?,
+        })
+    }
+
+    pub const fn text(body: String) -> Self {
+        Self { status: 200, content_type: "text/plain; charset=utf-8", body }
+    }
+
+    pub const fn html(body: String) -> Self {
+        Self { status: 200, content_type: "text/html; charset=utf-8", body }
+    }
+
+    pub fn bad_request(message: &str) -> Self {
+        Self { status: 400, ..Self::text(message.to_string()) }
+    }
+
+    fn error(status: u16, message: &str, format: Format) -> Self {
+        match format {
+            Format::Json => Self {
+                status,
+                content_type: "application/json",
+                body: serde_json::json!({ "error": message }).to_string(),
+            },
+            Format::Text => Self { status, ..Self::text(message.to_string()) },
+        }
+    }
+
+    pub fn not_found() -> Self {
+        Self { status: 404, ..Self::text(String::from("Not found")) }
+    }
+}
+
+pub struct Request {
+    pub segments: Vec<String>,
+    pub params: HashMap<String, String>,
+}
+
+impl Request {
+    fn parse(url: &str) -> Self {
+        let (path, query) = url.split_at(url.find('?').unwrap_or(url.len()));
+        let segments = path
+            .split('/')
+            .filter(|x| !x.is_empty())
+            .map(|x| percent_decode_str(x).decode_utf8_lossy().to_string())
+            .collect();
+        let params =
+            form_urlencoded::parse(query.trim_start_matches('?').as_bytes()).into_owned().collect();
+
+        Self { segments, params }
+    }
+
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.get(name).map(String::as_str).filter(|x| !x.is_empty())
+    }
+
+    fn format(&self) -> Format {
+        match self.param("format") {
+            Some("text" | "txt") => Format::Text,
+            _ => Format::Json,
+        }
+    }
+
+    fn limit(&self) -> u32 {
+        self.param("limit").and_then(|x| x.parse().ok()).unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT)
+    }
+}
+
+// Serves the API from a few worker threads, independent of the IRC connection.
+pub fn spawn(addr: &str, source: &Source) -> Result<(), error::Error> {
+    let server = Arc::new(tiny_http::Server::http(addr).map_err(error::Error::Http)?);
+
+    for _ in 0..WORKERS {
+        let server = Arc::clone(&server);
+        let source = source.clone();
+
+        thread::spawn(move || loop {
+            match server.recv() {
+                Ok(request) => {
+                    if let Err(e) = respond(&source, request) {
+                        eprintln!("Unable to send HTTP response: {}", e);
+                    }
+                }
+                Err(e) => eprintln!("Unable to receive HTTP request: {}", e),
+            }
+        });
+    }
+
+    println!("Serving HTTP API on {}...", addr);
+
+    Ok(())
+}
+
+fn respond(source: &Source, request: tiny_http::Request) -> Result<(), io::Error> {
+    let parsed = Request::parse(request.url());
+    let format = parsed.format();
+
+    let reply = if request.method() == &tiny_http::Method::Get {
+        route(source, &parsed).unwrap_or_else(|e| {
+            eprintln!("HTTP request {} failed: {}", request.url(), e);
+            Reply::error(500, "Internal server error", format)
+        })
+    } else {
+        Reply::error(405, "Method not allowed", format)
+    };
+
+    let header = tiny_http::Header::from_bytes(&b"Content-Type"[..], reply.content_type.as_bytes())
+        .expect("Invalid content type header");
+
+    request.respond(
+        tiny_http::Response::from_string(reply.body)
+            .with_status_code(reply.status)
+            .with_header(header),
+    )
+}
+
+fn route(source: &Source, request: &Request) -> Result<Reply, error::Error> {
+    let segments: Vec<&str> = request.segments.iter().map(String::as_str).collect();
+
+    match segments.as_slice() {
+        [] => viewer::channels(source),
+        ["view", name] => viewer::day(source, name, request),
+        ["channels"] => channels(source, request),
+        ["channels", name, "logs"] => logs(source, name, request),
+        ["users", name, "messages"] => messages(source, name, request),
+        ["search"] => search(source, request),
+        ["metrics"] => Ok(Reply::text(metrics::render())),
+        ["healthz"] => health::live(),
+        ["readyz"] => health::ready(),
+        ["list"] => justlog::list(source, request),
+        ["channel", name, rest @ ..] => justlog::channel(source, name, rest, request),
+        _ => Ok(Reply::not_found()),
+    }
+}
+
+fn hits(hits: &[search::Hit], format: Format) -> Result<Reply, error::Error> {
+    match format {
+        Format::Json => Reply::json(&hits),
+        Format::Text => Ok(Reply::text(hits.iter().map(|x| x.line() + "\n").collect::<String>())),
+    }
+}
+
+pub fn find(
+    source: &Source,
+    query: &search::Query,
+    limit: u32,
+) -> Result<Vec<search::Hit>, error::Error> {
+    match source {
+        Source::Store(store) => store.search(query, limit),
+        Source::Files => Ok(index::search(query, limit)?.into_iter().map(|x| x.hit).collect()),
+    }
+}
+
+pub fn channel_names(source: &Source) -> Result<Vec<String>, error::Error> {
+    match source {
+        Source::Store(store) => store.channels(),
+        Source::Files => file::channels(),
+    }
+}
+
+// Reading from text logs, only lines written since the index was created are returned.
+pub fn day(
+    source: &Source,
+    channel: &str,
+    since: DateTime<Utc>,
+    until: DateTime<Utc>,
+) -> Result<Vec<search::Hit>, error::Error> {
+    match source {
+        Source::Store(store) => store.logs(channel, since, until),
+        Source::Files => {
+            let query = search::Query {
+                channel: Some(channel.to_string()),
+                since: Some(since),
+                until: Some(until),
+                ..search::Query::default()
+            };
+            let mut day = find(source, &query, DAY_LIMIT)?;
+
+            day.reverse();
+
+            Ok(day)
+        }
+    }
+}
+
+pub fn moderation(
+    source: &Source,
+    channel: &str,
+    since: DateTime<Utc>,
+    until: DateTime<Utc>,
+) -> Result<Vec<search::Moderation>, error::Error> {
+    match source {
+        Source::Store(store) => store.moderation(channel, since, until),
+        Source::Files => {
+            let mut events = Vec::new();
+            let mut day = since.naive_utc().date();
+
+            while start(day) < until {
+                events.extend(file::moderation(channel, day)?);
+
+                match day.succ_opt() {
+                    Some(next) => day = next,
+                    None => break,
+                }
+            }
+
+            Ok(events)
+        }
+    }
+}
+
+// Reading from text logs, days with messages from `user` are only found among indexed lines.
+pub fn days(
+    source: &Source,
+    channel: &str,
+    user: Option<&str>,
+) -> Result<Vec<NaiveDate>, error::Error> {
+    match (source, user) {
+        (Source::Store(store), _) => store.days(channel, user),
+        (Source::Files, None) => file::days(channel),
+        (Source::Files, Some(user)) => {
+            let query = search::Query {
+                channel: Some(channel.to_string()),
+                user: Some(user.to_string()),
+                ..search::Query::default()
+            };
+            let mut days: Vec<NaiveDate> = find(source, &query, DAY_LIMIT)?
+                .iter()
+                .map(|x| x.timestamp.naive_utc().date())
+                .collect();
+
+            days.dedup();
+
+            Ok(days)
+        }
+    }
+}
+
+pub fn start(day: NaiveDate) -> DateTime<Utc> {
+    Utc.from_utc_datetime(&day.and_hms(0, 0, 0))
+}
+
+// `date` as a UTC day, defaulting to today.
+pub fn date(request: &Request) -> Result<DateTime<Utc>, String> {
+    request
+        .param("date")
+        .map_or_else(|| Ok(start(Utc::now().naive_utc().date())), search::parse_day)
+}
+
+// The channel named in a URL, if it could be one.
+pub fn channel(name: &str) -> Result<String, String> {
+    let channel = format!("#{}", name.trim_start_matches('#').to_lowercase());
+
+    if file::valid_channel(&channel) {
+        Ok(channel)
+    } else {
+        Err(format!("Invalid channel: {}", name))
+    }
+}
+
+fn channels(source: &Source, request: &Request) -> Result<Reply, error::Error> {
+    let channels = channel_names(source)?;
+
+    match request.format() {
+        Format::Json => Reply::json(&channels),
+        Format::Text => Ok(Reply::text(channels.iter().map(|x| x.clone() + "\n").collect())),
+    }
+}
+
+// One UTC day of a channel, oldest first; `date` defaults to today.
+fn logs(source: &Source, name: &str, request: &Request) -> Result<Reply, error::Error> {
+    let format = request.format();
+    let since = match date(request) {
+        Ok(since) => since,
+        Err(e) => return Ok(Reply::error(400, &e, format)),
+    };
+    let until = since + chrono::Duration::days(1);
+    let channel = match channel(name) {
+        Ok(channel) => channel,
+        Err(e) => return Ok(Reply::error(400, &e, format)),
+    };
+
+    match (source, format) {
+        (Source::Files, Format::Text) => {
+            match fs::read_to_string(file::log_path(&channel, since.naive_utc().date())?) {
+                Ok(body) => Ok(Reply::text(body)),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(Reply::not_found()),
+                Err(e) => Err(e.into()),
+            }
+        }
+        _ => hits(&day(source, &channel, since, until)?, format),
+    }
+}
+
+// Newest first, optionally narrowed with `channel`, `since` and `until`.
+fn messages(source: &Source, name: &str, request: &Request) -> Result<Reply, error::Error> {
+    let mut args = vec![format!("from:{}", name)];
+
+    if let Some(channel) = request.param("channel") {
+        args.push(format!("#{}", channel.trim_start_matches('#')));
+    }
+
+    for filter in &["since", "until"] {
+        if let Some(day) = request.param(filter) {
+            args.push(format!("{}:{}", filter, day));
+        }
+    }
+
+    query(source, &args, request)
+}
+
+// `q` takes the same filters and text as the `search` subcommand.
+fn search(source: &Source, request: &Request) -> Result<Reply, error::Error> {
+    let args: Vec<String> =
+        request.param("q").unwrap_or("").split_whitespace().map(String::from).collect();
+
+    query(source, &args, request)
+}
+
+fn query(source: &Source, args: &[String], request: &Request) -> Result<Reply, error::Error> {
+    match search::Query::parse(args) {
+        Ok(query) => hits(&find(source, &query, request.limit())?, request.format()),
+        Err(e) => Ok(Reply::error(400, &e, request.format())),
+    }
+}
@@ -0,0 +1,227 @@
+use chrono::prelude::*;
+use std::ops::Bound;
+use std::{fs, path, time};
+use tantivy::collector::TopDocs;
+use tantivy::query::{AllQuery, BooleanQuery, Occur, Query, QueryParser, RangeQuery, TermQuery};
+use tantivy::schema::{IndexRecordOption, Schema, Value, FAST, INDEXED, STORED, STRING, TEXT};
+use tantivy::{doc, Order, TantivyDocument, Term};
+
+use crate::lib::{error, message, search};
+
+// The index sits next to the text logs it points into and can be rebuilt by deleting it.
+const DIR: &str = "logs/index";
+const WRITER_MEMORY: usize = 15_000_000;
+
+// Documents only become searchable once committed, and each commit writes a new segment, so
+// commits are spaced out rather than made per message.
+const COMMIT_INTERVAL: time::Duration = time::Duration::from_secs(10);
+
+fn schema() -> Schema {
+    let mut schema = Schema::builder();
+
+    schema.add_text_field("channel", STRING | STORED);
+    schema.add_text_field("user", STRING | STORED);
+    schema.add_text_field("message", TEXT | STORED);
+    schema.add_i64_field("timestamp", INDEXED | FAST | STORED);
+    schema.add_text_field("path", STORED);
+    schema.add_u64_field("offset", STORED);
+
+    schema.build()
+}
+
+fn open_index() -> Result<tantivy::Index, error::Error> {
+    fs::create_dir_all(DIR)?;
+
+    if path::Path::new(DIR).join("meta.json").exists() {
+        Ok(tantivy::Index::open_in_dir(DIR)?)
+    } else {
+        Ok(tantivy::Index::create_in_dir(DIR, schema())?)
+    }
+}
+
+// Indexes lines as `file::Logger` writes them. Only one writer can hold the index at a time;
+// searches open their own reader.
+pub struct Index {
+    writer: tantivy::IndexWriter,
+    schema: Schema,
+    pending: usize,
+    committed_at: time::Instant,
+}
+
+impl Index {
+    pub fn open() -> Result<Self, error::Error> {
+        let index = open_index()?;
+
+        Ok(Self {
+            writer: index.writer_with_num_threads(1, WRITER_MEMORY)?,
+            schema: index.schema(),
+            pending: 0,
+            committed_at: time::Instant::now(),
+        })
+    }
+
+    pub fn add(
+        &mut self,
+        parsed_msg: &message::Message,
+        path: &str,
+        offset: u64,
+    ) -> Result<(), error::Error> {
+        let message = if parsed_msg.user_msg.is_empty() {
+            &parsed_msg.system_msg
+        } else {
+            &parsed_msg.user_msg
+        };
+
+        self.writer.add_document(doc!(
+            self.schema.get_field("channel")? => parsed_msg.target.as_str(),
+            self.schema.get_field("user")? => parsed_msg.username.as_str(),
+            self.schema.get_field("message")? => message.as_str(),
+            self.schema.get_field("timestamp")? => parsed_msg.timestamp.timestamp(),
+            self.schema.get_field("path")? => path,
+            self.schema.get_field("offset")? => offset,
+        ))?;
+
+        self.pending += 1;
+
+        self.tick()
+    }
+
+    // Commits at most once per interval, however busy the channels are, and picks up lines
+    // left over from a quiet channel once it has passed.
+    pub fn tick(&mut self) -> Result<(), error::Error> {
+        if self.pending > 0 && self.committed_at.elapsed() >= COMMIT_INTERVAL {
+            self.commit()?;
+        }
+
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<(), error::Error> {
+        self.writer.commit()?;
+        self.pending = 0;
+        self.committed_at = time::Instant::now();
+
+        Ok(())
+    }
+}
+
+impl Drop for Index {
+    fn drop(&mut self) {
+        if self.pending > 0 {
+            if let Err(e) = self.commit() {
+                eprintln!("Unable to commit search index: {}", e);
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Match {
+    pub hit: search::Hit,
+    pub path: String,
+    pub offset: u64,
+}
+
+impl Match {
+    // The matching line and up to `lines` lines on either side, read back from the log file.
+    // The matching line is marked with `>`.
+    pub fn context(&self, lines: usize) -> Result<Vec<String>, error::Error> {
+        let text = fs::read_to_string(&self.path)?;
+        let mut start = 0;
+        let mut found = 0;
+        let all: Vec<&str> = text.lines().collect();
+
+        for (i, line) in all.iter().enumerate() {
+            if start as u64 > self.offset {
+                break;
+            }
+
+            found = i;
+            start += line.len() + 1;
+        }
+
+        let first = found.saturating_sub(lines);
+        let last = (found + lines + 1).min(all.len());
+
+        Ok(all[first..last]
+            .iter()
+            .enumerate()
+            .map(|(i, line)| {
+                let marker = if first + i == found { '>' } else { ' ' };
+
+                format!("{} {}", marker, line)
+            })
+            .collect())
+    }
+}
+
+// Newest matches first. The text goes through tantivy's query parser, so "quoted words" match
+// as a phrase.
+pub fn search(query: &search::Query, limit: u32) -> Result<Vec<Match>, error::Error> {
+    let index = open_index()?;
+    let schema = index.schema();
+    let channel = schema.get_field("channel")?;
+    let user = schema.get_field("user")?;
+    let message = schema.get_field("message")?;
+    let mut clauses: Vec<(Occur, Box<dyn Query>)> = Vec::new();
+
+    if query.text.is_empty() {
+        clauses.push((Occur::Must, Box::new(AllQuery)));
+    } else {
+        let parser = QueryParser::for_index(&index, vec![message]);
+
+        clauses.push((Occur::Must, parser.parse_query(&query.text)?));
+    }
+
+    for (field, value) in &[(channel, &query.channel), (user, &query.user)] {
+        if let Some(value) = value {
+            let term = Term::from_field_text(*field, value);
+
+            clauses.push((Occur::Must, Box::new(TermQuery::new(term, IndexRecordOption::Basic))));
+        }
+    }
+
+    if query.since.is_some() || query.until.is_some() {
+        let since = query.since.map_or(Bound::Unbounded, |x| Bound::Included(x.timestamp()));
+        let until = query.until.map_or(Bound::Unbounded, |x| Bound::Excluded(x.timestamp()));
+
+        clauses.push((
+            Occur::Must,
+            Box::new(RangeQuery::new_i64_bounds(String::from("timestamp"), since, until)),
+        ));
+    }
+
+    let searcher = index.reader()?.searcher();
+    let top =
+        TopDocs::with_limit(limit as usize).order_by_fast_field::<i64>("timestamp", Order::Desc);
+    let mut matches = Vec::new();
+
+    for (timestamp, address) in searcher.search(&BooleanQuery::new(clauses), &top)? {
+        let doc: TantivyDocument = searcher.doc(address)?;
+        let text = |name: &str| -> Result<String, error::Error> {
+            Ok(doc
+                .get_first(schema.get_field(name)?)
+                .and_then(|x| x.as_str())
+                .unwrap_or("")
+                .to_string())
+        };
+
+        matches.push(Match {
+            hit: search::Hit {
+                msg_id: String::new(),
+                target: text("channel")?,
+                username: text("user")?,
+                system_msg: String::new(),
+                user_msg: text("message")?,
+                timestamp: Utc.timestamp_opt(timestamp, 0).single().unwrap_or_else(Utc::now),
+            },
+            path: text("path")?,
+            offset: doc
+                .get_first(schema.get_field("offset")?)
+                .and_then(|x| x.as_u64())
+                .unwrap_or(0),
+        });
+    }
+
+    Ok(matches)
+}
@@ -0,0 +1,116 @@
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::path;
+
+use crate::lib::{error, message};
+
+const DIR: &str = "journal";
+const ACTIVE: &str = "journal/active.jsonl";
+
+// Messages bound for the database are appended here before they are buffered, so a crash
+// or reconnect never loses more than what was still in flight to the operating system.
+#[derive(Debug)]
+pub struct Journal {
+    pub file: std::fs::File,
+    seq: u64,
+}
+
+impl Journal {
+    pub fn open() -> Result<Self, error::Error> {
+        fs::create_dir_all(DIR)?;
+
+        let file = fs::OpenOptions::new().create(true).append(true).open(ACTIVE)?;
+        let mut journal = Self { file, seq: 0 };
+
+        // Anything left in the active segment was never handed to the writer.
+        if journal.file.metadata()?.len() > 0 {
+            journal.seal()?;
+        }
+
+        Ok(journal)
+    }
+
+    pub fn append(&mut self, parsed_msg: &message::Message) -> Result<(), error::Error> {
+        let mut line = serde_json::to_vec(parsed_msg)?;
+
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+
+        Ok(())
+    }
+
+    // Closes the active segment and hands back its path; the segment is removed once its
+    // batch has been committed.
+    pub fn seal(&mut self) -> Result<path::PathBuf, error::Error> {
+        self.seq += 1;
+
+        let sealed = path::Path::new(DIR).join(format!(
+            "{}-{:06}.jsonl",
+            chrono::Utc::now().format("%Y%m%d%H%M%S%f"),
+            self.seq
+        ));
+
+        fs::rename(ACTIVE, &sealed)?;
+        self.file = fs::OpenOptions::new().create(true).append(true).open(ACTIVE)?;
+
+        Ok(sealed)
+    }
+}
+
+pub fn sealed() -> Result<Vec<path::PathBuf>, error::Error> {
+    if !path::Path::new(DIR).exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut segments = Vec::new();
+
+    for entry in fs::read_dir(DIR)? {
+        let path = entry?.path();
+
+        if path.extension() == Some(std::ffi::OsStr::new("jsonl"))
+            && path != path::Path::new(ACTIVE)
+        {
+            segments.push(path);
+        }
+    }
+
+    segments.sort();
+
+    Ok(segments)
+}
+
+pub fn read(segment: &path::Path) -> Result<Vec<message::Message>, error::Error> {
+    let file = fs::OpenOptions::new().read(true).open(segment)?;
+    let mut v = Vec::new();
+
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+
+        // A crash mid-write can leave a truncated final line behind.
+        match serde_json::from_str(&line) {
+            Ok(parsed_msg) => v.push(parsed_msg),
+            Err(e) => eprintln!("Skipping journal entry in {}: {}", segment.display(), e),
+        }
+    }
+
+    Ok(v)
+}
+
+pub fn remove(segment: &path::Path) -> Result<(), error::Error> {
+    fs::remove_file(segment)?;
+
+    Ok(())
+}
+
+// Batches Postgres refuses outright are kept aside for inspection instead of being retried.
+pub fn reject(segment: &path::Path) -> Result<(), error::Error> {
+    let dir = path::Path::new(DIR).join("rejected");
+
+    fs::create_dir_all(&dir)?;
+
+    if let Some(name) = segment.file_name() {
+        fs::rename(segment, dir.join(name))?;
+    }
+
+    Ok(())
+}
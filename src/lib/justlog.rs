@@ -0,0 +1,357 @@
+use chrono::prelude::*;
+use std::collections::BTreeMap;
+
+use crate::lib::{error, http, search};
+
+// Upper bound on messages returned for one user in one month.
+const MONTH_LIMIT: u32 = 100_000;
+
+// Message types as numbered by go-twitch-irc, which justlog clients read from `type`.
+const PRIVMSG: i32 = 1;
+const CLEARCHAT: i32 = 2;
+const USERNOTICE: i32 = 4;
+const CLEARMSG: i32 = 8;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Output {
+    Text,
+    Json,
+    Raw,
+}
+
+// justlog accepts both `?json` and `?type=json`, and likewise for `raw`.
+fn output(request: &http::Request) -> Output {
+    let flag = |name| request.params.contains_key(name) || request.param("type") == Some(name);
+
+    if flag("json") {
+        Output::Json
+    } else if flag("raw") {
+        Output::Raw
+    } else {
+        Output::Text
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Message {
+    text: String,
+    system_text: String,
+    username: String,
+    display_name: String,
+    channel: String,
+    timestamp: DateTime<Utc>,
+    id: String,
+    #[serde(rename = "type")]
+    kind: i32,
+    raw: String,
+    tags: BTreeMap<&'static str, String>,
+}
+
+#[derive(Serialize, Debug)]
+struct Log {
+    messages: Vec<Message>,
+}
+
+fn escape_tag(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace(';', "\\:")
+        .replace(' ', "\\s")
+        .replace('\r', "\\r")
+        .replace('\n', "\\n")
+}
+
+impl Message {
+    // Raw lines are rebuilt from the stored fields, so they carry fewer tags than Twitch sent.
+    fn new(
+        kind: i32,
+        channel: &str,
+        username: &str,
+        text: String,
+        system_text: String,
+        timestamp: DateTime<Utc>,
+        mut tags: BTreeMap<&'static str, String>,
+    ) -> Self {
+        let channel = channel.trim_start_matches('#').to_string();
+
+        tags.insert("tmi-sent-ts", timestamp.timestamp_millis().to_string());
+
+        let prefix: Vec<String> =
+            tags.iter().map(|(key, value)| format!("{}={}", key, escape_tag(value))).collect();
+        let source = if kind == PRIVMSG {
+            format!("{}!{}@{}.tmi.twitch.tv", username, username, username)
+        } else {
+            String::from("tmi.twitch.tv")
+        };
+        let command = match kind {
+            PRIVMSG => "PRIVMSG",
+            CLEARCHAT => "CLEARCHAT",
+            CLEARMSG => "CLEARMSG",
+            _ => "USERNOTICE",
+        };
+        let trailing = match kind {
+            CLEARCHAT if username.is_empty() => String::new(),
+            CLEARCHAT => format!(" :{}", username),
+            _ if text.is_empty() => String::new(),
+            _ => format!(" :{}", text),
+        };
+
+        Self {
+            raw: format!("@{} :{} {} #{}{}", prefix.join(";"), source, command, channel, trailing),
+            id: tags.get("id").cloned().unwrap_or_default(),
+            text,
+            system_text,
+            username: username.to_string(),
+            display_name: username.to_string(),
+            channel,
+            timestamp,
+            kind,
+            tags,
+        }
+    }
+
+    fn line(&self) -> String {
+        let timestamp = self.timestamp.format("%Y-%m-%d %H:%M:%S");
+
+        match self.kind {
+            PRIVMSG => {
+                format!("[{}] #{} {}: {}", timestamp, self.channel, self.username, self.text)
+            }
+            CLEARMSG => {
+                format!("[{}] #{} {}: {}", timestamp, self.channel, self.system_text, self.text)
+            }
+            _ if self.text.is_empty() => {
+                format!("[{}] #{} {}", timestamp, self.channel, self.system_text)
+            }
+            _ => format!("[{}] #{} {} {}", timestamp, self.channel, self.system_text, self.text),
+        }
+    }
+}
+
+impl From<&search::Hit> for Message {
+    fn from(hit: &search::Hit) -> Self {
+        let mut tags = BTreeMap::new();
+
+        if !hit.msg_id.is_empty() {
+            tags.insert("id", hit.msg_id.clone());
+        }
+
+        if hit.system_msg.is_empty() {
+            Self::new(
+                PRIVMSG,
+                &hit.target,
+                &hit.username,
+                hit.user_msg.clone(),
+                String::new(),
+                hit.timestamp,
+                tags,
+            )
+        } else {
+            tags.insert("login", hit.username.clone());
+            tags.insert("system-msg", hit.system_msg.clone());
+
+            Self::new(
+                USERNOTICE,
+                &hit.target,
+                &hit.username,
+                hit.user_msg.clone(),
+                hit.system_msg.clone(),
+                hit.timestamp,
+                tags,
+            )
+        }
+    }
+}
+
+fn event(channel: &str, event: &search::Moderation) -> Message {
+    let mut tags = BTreeMap::new();
+
+    if event.command == "CLEARMSG" {
+        tags.insert("login", event.username.clone());
+
+        if !event.target_msg_id.is_empty() {
+            tags.insert("target-msg-id", event.target_msg_id.clone());
+        }
+
+        Message::new(
+            CLEARMSG,
+            channel,
+            &event.username,
+            event.user_msg.clone(),
+            event.describe(),
+            event.timestamp,
+            tags,
+        )
+    } else {
+        if event.ban_duration > 0 {
+            tags.insert("ban-duration", event.ban_duration.to_string());
+        }
+
+        Message::new(
+            CLEARCHAT,
+            channel,
+            &event.username,
+            String::new(),
+            event.describe(),
+            event.timestamp,
+            tags,
+        )
+    }
+}
+
+// Oldest first unless `reverse` is given. Like justlog, an empty log is a 404.
+fn reply(mut messages: Vec<Message>, request: &http::Request) -> Result<http::Reply, error::Error> {
+    if messages.is_empty() {
+        return Ok(http::Reply::not_found());
+    }
+
+    messages.sort_by_key(|x| x.timestamp);
+
+    if request.params.contains_key("reverse") {
+        messages.reverse();
+    }
+
+    match output(request) {
+        Output::Json => http::Reply::json(&Log { messages }),
+        Output::Raw => {
+            Ok(http::Reply::text(messages.iter().map(|x| x.raw.clone() + "\n").collect()))
+        }
+        Output::Text => Ok(http::Reply::text(messages.iter().map(|x| x.line() + "\n").collect())),
+    }
+}
+
+fn date(year: &str, month: &str, day: &str) -> Option<NaiveDate> {
+    NaiveDate::from_ymd_opt(year.parse().ok()?, month.parse().ok()?, day.parse().ok()?)
+}
+
+// `None` past the last date chrono can represent.
+fn next_month(month: NaiveDate) -> Option<NaiveDate> {
+    if month.month() == 12 {
+        NaiveDate::from_ymd_opt(month.year() + 1, 1, 1)
+    } else {
+        NaiveDate::from_ymd_opt(month.year(), month.month() + 1, 1)
+    }
+}
+
+// `/channel/{channel}` and `/channel/{channel}/user/{user}` serve the current day and month
+// rather than redirecting to them.
+pub fn channel(
+    source: &http::Source,
+    name: &str,
+    rest: &[&str],
+    request: &http::Request,
+) -> Result<http::Reply, error::Error> {
+    let today = Utc::now().naive_utc().date();
+    let channel = match http::channel(name) {
+        Ok(channel) => channel,
+        Err(e) => return Ok(http::Reply::bad_request(&e)),
+    };
+
+    let (day, user) = match rest {
+        [] => (Some(today), None),
+        [year, month, day] => (date(year, month, day), None),
+        ["user", user] => (Some(today.with_day(1).unwrap_or(today)), Some(*user)),
+        ["user", user, year, month] => (date(year, month, "1"), Some(*user)),
+        _ => return Ok(http::Reply::not_found()),
+    };
+
+    // Days and months are served up to the start of the next, which must exist too.
+    let next = match user {
+        None => day.and_then(|x| x.succ_opt()),
+        Some(_) => day.and_then(next_month),
+    };
+
+    match (day, next, user) {
+        (Some(day), Some(next), None) => channel_logs(source, &channel, day, next, request),
+        (Some(month), Some(next), Some(user)) => {
+            user_logs(source, &channel, &user.to_lowercase(), month, next, request)
+        }
+        _ => Ok(http::Reply::bad_request("Invalid date")),
+    }
+}
+
+fn channel_logs(
+    source: &http::Source,
+    channel: &str,
+    day: NaiveDate,
+    next: NaiveDate,
+    request: &http::Request,
+) -> Result<http::Reply, error::Error> {
+    let since = http::start(day);
+    let until = http::start(next);
+    let mut messages: Vec<Message> =
+        http::day(source, channel, since, until)?.iter().map(Message::from).collect();
+
+    for x in http::moderation(source, channel, since, until)? {
+        messages.push(event(channel, &x));
+    }
+
+    reply(messages, request)
+}
+
+fn user_logs(
+    source: &http::Source,
+    channel: &str,
+    user: &str,
+    month: NaiveDate,
+    next: NaiveDate,
+    request: &http::Request,
+) -> Result<http::Reply, error::Error> {
+    let query = search::Query {
+        channel: Some(channel.to_string()),
+        user: Some(user.to_string()),
+        since: Some(http::start(month)),
+        until: Some(http::start(next)),
+        ..search::Query::default()
+    };
+    let mut messages: Vec<Message> =
+        http::find(source, &query, MONTH_LIMIT)?.iter().map(Message::from).collect();
+
+    for x in http::moderation(source, channel, http::start(month), http::start(next))? {
+        if x.username == user {
+            messages.push(event(channel, &x));
+        }
+    }
+
+    reply(messages, request)
+}
+
+// `/list?channel=...` lists days with logs, and adding `user` lists that user's months.
+pub fn list(source: &http::Source, request: &http::Request) -> Result<http::Reply, error::Error> {
+    let channel = match request.param("channel").map(http::channel) {
+        Some(Ok(channel)) => channel,
+        Some(Err(e)) => return Ok(http::Reply::bad_request(&e)),
+        None => return Ok(http::Reply::bad_request("A channel is required")),
+    };
+    let user = request.param("user").map(str::to_lowercase);
+    let days = http::days(source, &channel, user.as_deref())?;
+
+    let logs: Vec<serde_json::Value> = if user.is_some() {
+        let mut months: Vec<(i32, u32)> = days.iter().map(|x| (x.year(), x.month())).collect();
+
+        months.dedup();
+        months
+            .iter()
+            .map(|(year, month)| {
+                serde_json::json!({ "year": year.to_string(), "month": month.to_string() })
+            })
+            .collect()
+    } else {
+        days.iter()
+            .map(|x| {
+                serde_json::json!({
+                    "year": x.year().to_string(),
+                    "month": x.month().to_string(),
+                    "day": x.day().to_string(),
+                })
+            })
+            .collect()
+    };
+
+    if logs.is_empty() {
+        return Ok(http::Reply::not_found());
+    }
+
+    http::Reply::json(&serde_json::json!({ "availableLogs": logs }))
+}
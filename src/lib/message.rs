@@ -2,9 +2,9 @@ use chrono::prelude::*;
 use regex::Regex;
 use std::fmt;
 
-use crate::lib::{error, message};
+use crate::lib::{error, message, twitch};
 
-#[derive(Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum UserType {
     User,
     Moderator,
@@ -21,17 +21,27 @@ impl fmt::Display for UserType {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Message {
+    #[serde(default)]
+    pub msg_id: String,
     pub command: String,
     pub target: String,
+    #[serde(default)]
+    pub room_id: i32,
     pub user_id: i32,
     pub user_type: UserType,
     pub username: String,
+    #[serde(default)]
+    pub display_name: String,
     pub sub_count: i32,
     pub system_msg: String,
     pub user_msg: String,
     pub timestamp: chrono::DateTime<Utc>,
+    #[serde(default)]
+    pub target_msg_id: String,
+    #[serde(default)]
+    pub ban_duration: i32,
 }
 
 const fn check_user_type(user_type: i32) -> message::UserType {
@@ -41,25 +51,56 @@ const fn check_user_type(user_type: i32) -> message::UserType {
     }
 }
 
+// Twitch stamps messages when it relays them, so every connection that sees a message agrees on
+// its time; that is what lets duplicates be recognized across reconnects and bot instances.
+fn sent_at(sent_ts: &str) -> chrono::DateTime<Utc> {
+    sent_ts
+        .parse::<i64>()
+        .ok()
+        .and_then(|x| Utc.timestamp_millis_opt(x).single())
+        .unwrap_or_else(Utc::now)
+}
+
 impl Message {
     fn new() -> Self {
         Self {
+            msg_id: String::from(""),
             command: String::from(""),
             target: String::from(""),
+            room_id: 0,
             user_id: 0,
             user_type: UserType::NotSet,
             username: String::from(""),
+            display_name: String::from(""),
             sub_count: 0,
             system_msg: String::from(""),
             user_msg: String::from(""),
             timestamp: Utc::now(),
+            target_msg_id: String::from(""),
+            ban_duration: 0,
         }
     }
 
-    fn parse_whisper(raw_msg: &irc::proto::Message) -> Result<Self, error::Error> {
+    // Identifies a message that arrives more than once. Channel message ids are unique, but a
+    // whisper's is only unique within its thread, so it is paired with the sender.
+    pub fn seen_key(&self) -> String {
+        if self.command == "WHISPER" {
+            format!("{}:{}", self.user_id, self.msg_id)
+        } else {
+            self.msg_id.clone()
+        }
+    }
+
+    // Deletions, timeouts, bans and chat clears.
+    pub fn is_moderation(&self) -> bool {
+        self.command == "CLEARCHAT" || self.command == "CLEARMSG"
+    }
+
+    fn parse_whisper(raw_msg: &twitch::Message) -> Result<Self, error::Error> {
         lazy_static! {
             static ref RE: Regex = {
                 let pattern = [
+                    r"(?:message-id=(?P<msg_id>[^;]*).+)?",
                     r"user-id=(?P<user_id>\d*).+",
                     r":(?P<username>\w*)!\w*@\w*.tmi.twitch.tv\s",
                     r"(?P<command>WHISPER)\s",
@@ -74,25 +115,34 @@ impl Message {
 
         RE.captures(&raw_msg.to_string()).map_or(Ok(Self::new()), |data| {
             Ok(Self {
+                msg_id: data.name("msg_id").map_or("", |x| x.as_str()).to_string(),
                 command: data["command"].to_string(),
                 target: data["target"].to_string(),
+                room_id: 0,
                 user_id: data["user_id"].to_string().parse::<i32>().unwrap_or(0),
                 user_type: UserType::NotSet,
                 username: data["username"].to_string(),
+                display_name: String::from(""),
                 sub_count: 0,
                 system_msg: String::from(""),
                 user_msg: data["user_msg"].replace("\r", ""),
                 timestamp: Utc::now(),
+                target_msg_id: String::from(""),
+                ban_duration: 0,
             })
         })
     }
 
-    fn parse_privmsg(raw_msg: &irc::proto::Message) -> Result<Self, error::Error> {
+    fn parse_privmsg(raw_msg: &twitch::Message) -> Result<Self, error::Error> {
         lazy_static! {
             static ref RE: Regex = {
                 let pattern = [
                     r"(?:@badge-info=subscriber/(?P<sub_count>\d*))?.+",
+                    r"display-name=(?P<display_name>[^;]*).+",
+                    r";id=(?P<msg_id>[^;]*).+",
                     r"mod=(?P<user_type>\d*).+",
+                    r"room-id=(?P<room_id>\d*).+",
+                    r"tmi-sent-ts=(?P<sent_ts>\d*).+",
                     r"user-id=(?P<user_id>\d*).+",
                     r":(?P<username>\w*)!\w*@\w*.tmi.twitch.tv\s",
                     r"(?P<command>PRIVMSG)\s",
@@ -110,30 +160,38 @@ impl Message {
             let user_type = check_user_type(data["user_type"].parse::<i32>().unwrap_or(0));
 
             Ok(Self {
+                msg_id: data["msg_id"].to_string(),
                 command: data["command"].to_string(),
                 target: data["target"].to_string(),
+                room_id: data["room_id"].parse::<i32>().unwrap_or(0),
                 user_id: data["user_id"].to_string().parse::<i32>().unwrap_or(0),
                 user_type,
                 username: data["username"].to_string(),
+                display_name: data["display_name"].to_string(),
                 sub_count: sub_count.to_string().parse::<i32>().unwrap_or(0),
                 system_msg: String::from(""),
                 user_msg: data["user_msg"].replace("\r", ""),
-                timestamp: Utc::now(),
+                timestamp: sent_at(&data["sent_ts"]),
+                target_msg_id: String::from(""),
+                ban_duration: 0,
             })
         } else {
             Ok(Self::new())
         }
     }
 
-    fn parse_notice(raw_msg: &irc::proto::Message) -> Result<Self, error::Error> {
+    fn parse_notice(raw_msg: &twitch::Message) -> Result<Self, error::Error> {
         lazy_static! {
             static ref RE: Regex = {
                 let pattern = [
                     r"(?:@badge-info=subscriber/(?P<sub_count>\d*))?.+",
+                    r"display-name=(?P<display_name>[^;]*).+",
+                    r";id=(?P<msg_id>[^;]*).+",
                     r"login=(?P<username>\w*).+",
                     r"mod=(?P<user_type>\d*).+",
                     r"room-id=(?P<room_id>\d*).+",
-                    r"system-msg=(?P<system_msg>(.*?));.+",
+                    r"system-msg=(?P<system_msg>(.*?));.*",
+                    r"tmi-sent-ts=(?P<sent_ts>\d*).+",
                     r"user-id=(?P<user_id>\d*).+",
                     r"(?P<command>USERNOTICE)\s",
                     r"(?P<target>#\w*)",
@@ -146,18 +204,18 @@ impl Message {
         }
 
         if let Some(data) = RE.captures(&raw_msg.to_string()) {
-            let user_msg = data.get(10).map_or("", |x| x.as_str());
+            let user_msg = data.name("user_msg").map_or("", |x| x.as_str());
 
             #[allow(clippy::trivial_regex)]
             let re = Regex::new(r"\\s")?;
             let system_msg = re.replace_all(&data["system_msg"], " ").to_string();
 
             // If anonymous user gifts a sub
-            let (user_id, user_type, username, sub_count) = if &data["username"]
+            let (user_id, user_type, username, display_name, sub_count) = if &data["username"]
                 == "ananonymousgifter"
                 || &data["username"] == "ananonymouscheerer"
             {
-                (0, UserType::NotSet, "anonymous".to_string(), 0)
+                (0, UserType::NotSet, "anonymous".to_string(), "anonymous".to_string(), 0)
             } else {
                 let sub_count = data.get(1).map_or("0", |x| x.as_str());
 
@@ -165,30 +223,128 @@ impl Message {
                     data["user_id"].to_string().parse::<i32>().unwrap_or(0),
                     check_user_type(data["user_type"].parse::<i32>().unwrap_or(0)),
                     data["username"].to_string(),
+                    data["display_name"].to_string(),
                     sub_count.to_string().parse::<i32>().unwrap_or(0),
                 )
             };
 
             Ok(Self {
+                msg_id: data["msg_id"].to_string(),
                 command: data["command"].to_string(),
                 target: data["target"].to_string(),
+                room_id: data["room_id"].parse::<i32>().unwrap_or(0),
                 user_id,
                 user_type,
                 username,
+                display_name,
                 sub_count,
                 system_msg,
                 user_msg: user_msg.replace("\r", ""),
-                timestamp: Utc::now(),
+                timestamp: sent_at(&data["sent_ts"]),
+                target_msg_id: String::from(""),
+                ban_duration: 0,
             })
         } else {
             Ok(Self::new())
         }
     }
 
-    pub fn parse_msg(raw_msg: &irc::proto::Message) -> Result<Self, error::Error> {
+    // Timeouts and bans name the user in the trailing parameter; without one the whole chat
+    // was cleared.
+    fn parse_clearchat(raw_msg: &twitch::Message) -> Result<Self, error::Error> {
         lazy_static! {
             static ref RE: Regex = {
-                let pattern = [r"(?P<command>WHISPER|PRIVMSG|USERNOTICE)"].join("");
+                let pattern = [
+                    r"(?:ban-duration=(?P<ban_duration>\d*);)?",
+                    r"room-id=(?P<room_id>\d*);",
+                    r"(?:target-user-id=(?P<user_id>\d*);)?",
+                    r"tmi-sent-ts=(?P<sent_ts>\d*).*\s",
+                    r"(?P<command>CLEARCHAT)\s",
+                    r"(?P<target>#\w*)",
+                    r"(?:\s:(?P<username>\w+))?",
+                ]
+                .join("");
+
+                Regex::new(&pattern).unwrap()
+            };
+        }
+
+        if let Some(data) = RE.captures(&raw_msg.to_string()) {
+            let username = data.name("username").map_or("", |x| x.as_str());
+            let ban_duration =
+                data.name("ban_duration").map_or(0, |x| x.as_str().parse::<i32>().unwrap_or(0));
+
+            let system_msg = if username.is_empty() {
+                String::from("Chat was cleared")
+            } else if ban_duration > 0 {
+                format!("{} has been timed out for {} seconds", username, ban_duration)
+            } else {
+                format!("{} has been banned", username)
+            };
+
+            Ok(Self {
+                command: data["command"].to_string(),
+                target: data["target"].to_string(),
+                room_id: data["room_id"].parse::<i32>().unwrap_or(0),
+                user_id: data.name("user_id").map_or(0, |x| x.as_str().parse::<i32>().unwrap_or(0)),
+                username: username.to_string(),
+                system_msg,
+                timestamp: sent_at(&data["sent_ts"]),
+                ban_duration,
+                ..Self::new()
+            })
+        } else {
+            Ok(Self::new())
+        }
+    }
+
+    // The deleted text is kept in `user_msg` and also quoted in `system_msg`, which is what text
+    // logs show.
+    fn parse_clearmsg(raw_msg: &twitch::Message) -> Result<Self, error::Error> {
+        lazy_static! {
+            static ref RE: Regex = {
+                let pattern = [
+                    r"login=(?P<username>\w*);",
+                    r".*room-id=(?P<room_id>\d*);",
+                    r"target-msg-id=(?P<target_msg_id>[^;]*);",
+                    r"tmi-sent-ts=(?P<sent_ts>\d*).*\s",
+                    r"(?P<command>CLEARMSG)\s",
+                    r"(?P<target>#\w*)\s",
+                    r":(?P<user_msg>.+)",
+                ]
+                .join("");
+
+                Regex::new(&pattern).unwrap()
+            };
+        }
+
+        if let Some(data) = RE.captures(&raw_msg.to_string()) {
+            let user_msg = data["user_msg"].replace("\r", "");
+
+            Ok(Self {
+                command: data["command"].to_string(),
+                target: data["target"].to_string(),
+                room_id: data["room_id"].parse::<i32>().unwrap_or(0),
+                username: data["username"].to_string(),
+                system_msg: format!(
+                    "A message from {} was deleted: {}",
+                    &data["username"], user_msg
+                ),
+                user_msg,
+                timestamp: sent_at(&data["sent_ts"]),
+                target_msg_id: data["target_msg_id"].to_string(),
+                ..Self::new()
+            })
+        } else {
+            Ok(Self::new())
+        }
+    }
+
+    pub fn parse_msg(raw_msg: &twitch::Message) -> Result<Self, error::Error> {
+        lazy_static! {
+            static ref RE: Regex = {
+                let pattern =
+                    [r"(?P<command>WHISPER|PRIVMSG|USERNOTICE|CLEARCHAT|CLEARMSG)"].join("");
 
                 Regex::new(&pattern).unwrap()
             };
@@ -199,6 +355,8 @@ impl Message {
                 "WHISPER" => return Self::parse_whisper(raw_msg),
                 "PRIVMSG" => return Self::parse_privmsg(raw_msg),
                 "USERNOTICE" => return Self::parse_notice(raw_msg),
+                "CLEARCHAT" => return Self::parse_clearchat(raw_msg),
+                "CLEARMSG" => return Self::parse_clearmsg(raw_msg),
                 _ => return Ok(Self::new()),
             }
         }
@@ -206,3 +364,30 @@ impl Message {
         Ok(Self::new())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lib::seen;
+
+    fn whisper(user: &str, user_id: i32) -> Message {
+        let line = format!(
+            "@badges=;color=;display-name={0};emotes=;message-id=1;thread-id=9_{1};turbo=0;\
+                user-id={1};user-type= :{0}!{0}@{0}.tmi.twitch.tv WHISPER bot :uptime",
+            user, user_id
+        );
+
+        Message::parse_msg(&line.parse().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn keeps_whispers_from_different_admins_apart() {
+        let (first, second) = (whisper("boss", 7), whisper("chief", 8));
+        let mut seen = seen::Seen::default();
+
+        assert_eq!((first.msg_id.as_str(), second.msg_id.as_str()), ("1", "1"));
+        assert!(seen.insert(&first.seen_key()));
+        assert!(seen.insert(&second.seen_key()));
+        assert!(!seen.insert(&whisper("boss", 7).seen_key()));
+    }
+}
@@ -0,0 +1,288 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time;
+
+use crate::lib::{config, store, twitch};
+
+const PREFIX: &str = "twitch_log_bot";
+const BATCH_ROWS: &[f64] = &[10.0, 50.0, 100.0, 500.0, 1000.0, 5000.0, 10000.0];
+const BATCH_SECONDS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+pub static WRITE_ERRORS: AtomicU64 = AtomicU64::new(0);
+pub static RECONNECTS: AtomicU64 = AtomicU64::new(0);
+pub static CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+pub static RAW_QUEUE: AtomicUsize = AtomicUsize::new(0);
+
+// A per-channel counter: its name, help text and value.
+type Counter = (&'static str, &'static str, fn(&Counts) -> u64);
+
+// A sink's queue: messages waiting for its writer, and those dropped because it was full.
+#[derive(Debug, Default)]
+pub struct Queue {
+    pub depth: AtomicUsize,
+    pub dropped: AtomicU64,
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+struct Counts {
+    received: u64,
+    parsed: u64,
+    dropped: u64,
+}
+
+// Buckets hold cumulative counts, as they are exported.
+struct Histogram {
+    bounds: &'static [f64],
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self { bounds, buckets: vec![0; bounds.len()], sum: 0.0, count: 0 }
+    }
+
+    fn observe(&mut self, value: f64) {
+        for (bound, bucket) in self.bounds.iter().zip(self.buckets.iter_mut()) {
+            if value <= *bound {
+                *bucket += 1;
+            }
+        }
+
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn render(&self, lines: &mut Vec<String>, name: &str, help: &str) {
+        header(lines, name, help, "histogram");
+
+        for (bound, bucket) in self.bounds.iter().zip(&self.buckets) {
+            lines.push(format!("{}_{}_bucket{{le=\"{}\"}} {}", PREFIX, name, bound, bucket));
+        }
+
+        lines.push(format!("{}_{}_bucket{{le=\"+Inf\"}} {}", PREFIX, name, self.count));
+        lines.push(format!("{}_{}_sum {}", PREFIX, name, self.sum));
+        lines.push(format!("{}_{}_count {}", PREFIX, name, self.count));
+    }
+}
+
+lazy_static! {
+    static ref CHANNELS: Mutex<BTreeMap<String, Counts>> = Mutex::new(BTreeMap::new());
+    static ref BATCHES: Mutex<(Histogram, Histogram)> =
+        Mutex::new((Histogram::new(BATCH_ROWS), Histogram::new(BATCH_SECONDS)));
+    static ref STATE: Mutex<Option<config::State>> = Mutex::new(None);
+    static ref QUEUES: Mutex<BTreeMap<&'static str, Arc<Queue>>> = Mutex::new(BTreeMap::new());
+}
+
+// The channel of a message the bot logs, read before parsing so failures can be counted.
+pub fn channel(raw_msg: &twitch::Message) -> Option<&str> {
+    let target = match raw_msg.command.as_str() {
+        "PRIVMSG" | "USERNOTICE" | "CLEARCHAT" | "CLEARMSG" => raw_msg.args.first(),
+        _ => None,
+    };
+
+    target.map(String::as_str).filter(|x| x.starts_with('#'))
+}
+
+// A message is dropped when it was not written to any sink: it failed to parse, logging was
+// paused, or its channel is routed to no running sink.
+pub fn message(channel: &str, parsed: bool, logged: bool) {
+    let mut channels = CHANNELS.lock().expect("Unable to acquire metrics mutex");
+    let counts = channels.entry(channel.to_string()).or_default();
+
+    counts.received += 1;
+    counts.parsed += u64::from(parsed);
+    counts.dropped += u64::from(!logged);
+}
+
+#[allow(clippy::cast_precision_loss)]
+pub fn batch(rows: usize, elapsed: time::Duration) {
+    let mut batches = BATCHES.lock().expect("Unable to acquire metrics mutex");
+
+    batches.0.observe(rows as f64);
+    batches.1.observe(elapsed.as_secs_f64());
+}
+
+// The queue for `sink`, kept across reconnects so drops keep counting up. A new run starts
+// with an empty queue.
+pub fn queue(sink: &'static str) -> Arc<Queue> {
+    let mut queues = QUEUES.lock().expect("Unable to acquire metrics mutex");
+    let queue = queues.entry(sink).or_default();
+
+    queue.depth.store(0, Ordering::Relaxed);
+
+    Arc::clone(queue)
+}
+
+// A copy of the current connection's state, published when it starts and whenever a command
+// changes it, so probes never wait on a command holding the bot state mutex.
+pub fn publish(state: config::State) {
+    *STATE.lock().expect("Unable to acquire metrics mutex") = Some(state);
+}
+
+// The last published state, once connected.
+pub fn state() -> Option<config::State> {
+    *STATE.lock().expect("Unable to acquire metrics mutex")
+}
+
+fn header(lines: &mut Vec<String>, name: &str, help: &str, kind: &str) {
+    lines.push(format!("# HELP {}_{} {}", PREFIX, name, help));
+    lines.push(format!("# TYPE {}_{} {}", PREFIX, name, kind));
+}
+
+fn metric(lines: &mut Vec<String>, name: &str, help: &str, kind: &str, value: impl fmt::Display) {
+    header(lines, name, help, kind);
+    lines.push(format!("{}_{} {}", PREFIX, name, value));
+}
+
+fn label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+// How far the parser and each sink have fallen behind.
+fn queues(lines: &mut Vec<String>) {
+    let queues = QUEUES.lock().expect("Unable to acquire metrics mutex");
+
+    metric(
+        lines,
+        "raw_queue_depth",
+        "Lines read from Twitch waiting to be parsed.",
+        "gauge",
+        RAW_QUEUE.load(Ordering::Relaxed),
+    );
+
+    header(lines, "sink_queue_depth", "Messages waiting to be written by a sink.", "gauge");
+
+    for (sink, queue) in queues.iter() {
+        lines.push(format!(
+            "{}_sink_queue_depth{{sink=\"{}\"}} {}",
+            PREFIX,
+            sink,
+            queue.depth.load(Ordering::Relaxed)
+        ));
+    }
+
+    header(
+        lines,
+        "sink_queue_dropped_total",
+        "Messages a sink dropped because its queue was full.",
+        "counter",
+    );
+
+    for (sink, queue) in queues.iter() {
+        lines.push(format!(
+            "{}_sink_queue_dropped_total{{sink=\"{}\"}} {}",
+            PREFIX,
+            sink,
+            queue.dropped.load(Ordering::Relaxed)
+        ));
+    }
+}
+
+// Every metric in the Prometheus text exposition format.
+pub fn render() -> String {
+    let mut lines = Vec::new();
+    let channels = CHANNELS.lock().expect("Unable to acquire metrics mutex").clone();
+
+    let per_channel: [Counter; 3] = [
+        ("messages_received_total", "Channel messages received from Twitch.", |x| x.received),
+        ("messages_parsed_total", "Channel messages parsed successfully.", |x| x.parsed),
+        ("messages_dropped_total", "Channel messages that were not logged.", |x| x.dropped),
+    ];
+
+    for (name, help, value) in &per_channel {
+        header(&mut lines, name, help, "counter");
+
+        for (channel, counts) in &channels {
+            lines.push(format!(
+                "{}_{}{{channel=\"{}\"}} {}",
+                PREFIX,
+                name,
+                label(channel),
+                value(counts)
+            ));
+        }
+    }
+
+    queues(&mut lines);
+
+    {
+        let batches = BATCHES.lock().expect("Unable to acquire metrics mutex");
+
+        batches.0.render(&mut lines, "db_batch_rows", "Rows per database batch written.");
+        batches.1.render(&mut lines, "db_batch_seconds", "Time taken to write a database batch.");
+    }
+
+    metric(
+        &mut lines,
+        "db_write_errors_total",
+        "Failed database batch writes.",
+        "counter",
+        WRITE_ERRORS.load(Ordering::Relaxed),
+    );
+    metric(
+        &mut lines,
+        "db_pending_rows",
+        "Rows waiting in memory for the database.",
+        "gauge",
+        store::PENDING.load(Ordering::Relaxed),
+    );
+    metric(
+        &mut lines,
+        "db_spilled_batches",
+        "Batches waiting on disk for the database.",
+        "gauge",
+        store::SPILLED.load(Ordering::Relaxed),
+    );
+    metric(
+        &mut lines,
+        "db_rejected_rows_total",
+        "Rows the database refused and moved to journal/rejected.",
+        "counter",
+        store::DROPPED.load(Ordering::Relaxed),
+    );
+    metric(
+        &mut lines,
+        "reconnects_total",
+        "Reconnects to Twitch after the connection failed.",
+        "counter",
+        RECONNECTS.load(Ordering::Relaxed),
+    );
+    metric(
+        &mut lines,
+        "connections",
+        "Open connections to Twitch.",
+        "gauge",
+        CONNECTIONS.load(Ordering::Relaxed),
+    );
+
+    if let Some(state) = state() {
+        metric(
+            &mut lines,
+            "buffer",
+            "Messages buffered per database batch.",
+            "gauge",
+            state.buffer,
+        );
+        metric(
+            &mut lines,
+            "paused",
+            "Whether logging is paused.",
+            "gauge",
+            u64::from(state.paused),
+        );
+        metric(
+            &mut lines,
+            "start_time_seconds",
+            "Unix time the current connection was started.",
+            "gauge",
+            state.uptime.timestamp(),
+        );
+    }
+
+    lines.push(String::new());
+    lines.join("\n")
+}
@@ -1,6 +1,23 @@
+pub mod bucket;
 pub mod commands;
 pub mod config;
 pub mod db;
 pub mod error;
 pub mod file;
+pub mod health;
+pub mod http;
+pub mod index;
+pub mod journal;
+pub mod justlog;
 pub mod message;
+pub mod metrics;
+pub mod outbox;
+pub mod pipeline;
+pub mod search;
+pub mod seen;
+pub mod shard;
+pub mod sink;
+pub mod sqlite;
+pub mod store;
+pub mod twitch;
+pub mod viewer;
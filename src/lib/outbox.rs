@@ -0,0 +1,193 @@
+use std::collections::{HashMap, VecDeque};
+use std::time;
+use tokio::sync::mpsc;
+
+use crate::lib::bucket;
+
+// Twitch cuts whispers off at 500 characters, counting the `/w <target> ` command.
+const MAX_LENGTH: usize = 500;
+// Whispers are limited to 3 a second and 100 a minute per account. Each recipient gets at most
+// one a second, so replies that pile up are sent together.
+const GLOBAL_LIMITS: [(u32, time::Duration); 2] =
+    [(3, time::Duration::from_secs(1)), (100, time::Duration::from_secs(60))];
+const TARGET_LIMIT: (u32, time::Duration) = (1, time::Duration::from_secs(1));
+const SEPARATOR: &str = " | ";
+
+// Whisper replies waiting for the rate limits, split to fit and joined up per recipient.
+pub struct Outbox {
+    nickname: String,
+    rx: mpsc::UnboundedReceiver<(String, String)>,
+    queue: VecDeque<(String, String)>,
+    global: Vec<bucket::Bucket>,
+    targets: HashMap<String, bucket::Bucket>,
+}
+
+impl Outbox {
+    pub fn new(nickname: &str, rx: mpsc::UnboundedReceiver<(String, String)>) -> Self {
+        Self {
+            nickname: nickname.to_string(),
+            rx,
+            queue: VecDeque::new(),
+            global: GLOBAL_LIMITS
+                .iter()
+                .map(|(limit, window)| bucket::Bucket::new(*limit, *window))
+                .collect(),
+            targets: HashMap::new(),
+        }
+    }
+
+    fn push(&mut self, target: &str, reply: &str) {
+        for piece in split(reply, room(target)) {
+            self.queue.push_back((target.to_string(), piece));
+        }
+    }
+
+    fn receive(&mut self) {
+        while let Ok((target, reply)) = self.rx.try_recv() {
+            self.push(&target, &reply);
+        }
+    }
+
+    // Takes the queued reply at `i`, along with the replies to the same recipient after it, as
+    // long as they fit in one whisper.
+    fn coalesce(&mut self, i: usize) -> (String, String) {
+        let (target, mut text) = self.queue.remove(i).expect("Queued reply exists");
+        let room = room(&target);
+
+        while let Some(j) = self.queue.iter().skip(i).position(|(x, _)| *x == target) {
+            let next = &self.queue[i + j].1;
+
+            if text.chars().count() + SEPARATOR.len() + next.chars().count() > room {
+                break;
+            }
+
+            text.push_str(SEPARATOR);
+            text.push_str(next);
+            self.queue.remove(i + j);
+        }
+
+        (target, text)
+    }
+
+    // The next line to send, once the rate limits allow it. A recipient that has to wait does
+    // not hold up the others. Returns `None` once every sender is gone and the queue is empty.
+    pub async fn next(&mut self) -> Option<String> {
+        if self.queue.is_empty() {
+            let (target, reply) = self.rx.recv().await?;
+
+            self.push(&target, &reply);
+        }
+
+        loop {
+            self.receive();
+
+            let mut delay = TARGET_LIMIT.1;
+            let targets = &mut self.targets;
+            let ready = self.queue.iter().position(|(target, _)| {
+                let bucket = targets
+                    .entry(target.clone())
+                    .or_insert_with(|| bucket::Bucket::new(TARGET_LIMIT.0, TARGET_LIMIT.1));
+
+                bucket.take().map_err(|wait| delay = delay.min(wait)).is_ok()
+            });
+
+            if let Some(i) = ready {
+                for bucket in &mut self.global {
+                    bucket.wait().await;
+                }
+
+                // Replies that arrived while waiting can still go out with this one.
+                self.receive();
+
+                let (target, text) = self.coalesce(i);
+
+                return Some(format!("PRIVMSG {} :/w {} {}", self.nickname, target, text));
+            }
+
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+// Characters left for the reply in a whisper to `target`.
+fn room(target: &str) -> usize {
+    MAX_LENGTH.saturating_sub(format!("/w {} ", target).chars().count()).max(1)
+}
+
+// Splits a reply into whispers of at most `room` characters, at spaces. Words too long for one
+// whisper are cut.
+fn split(reply: &str, room: usize) -> Vec<String> {
+    let mut pieces = Vec::new();
+    let mut piece = String::new();
+
+    for word in reply.split(' ') {
+        if !piece.is_empty() && piece.chars().count() + 1 + word.chars().count() > room {
+            pieces.push(std::mem::take(&mut piece));
+        }
+
+        if !piece.is_empty() {
+            piece.push(' ');
+        }
+
+        piece.push_str(word);
+
+        while let Some((i, _)) = piece.char_indices().nth(room) {
+            let rest = piece.split_off(i);
+
+            pieces.push(std::mem::replace(&mut piece, rest));
+        }
+    }
+
+    if !piece.is_empty() {
+        pieces.push(piece);
+    }
+
+    pieces
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_long_replies_at_spaces() {
+        let words = vec!["#channel"; 100].join(" ");
+        let pieces = split(&words, room("boss"));
+
+        assert_eq!(room("boss"), 492);
+        assert_eq!(pieces.len(), 2);
+        assert!(pieces.iter().all(|x| x.chars().count() <= 492 && !x.starts_with(' ')));
+        assert_eq!(pieces.join(" "), words);
+        assert_eq!(split(&"a".repeat(1200), room("boss")).len(), 3);
+    }
+
+    #[tokio::test]
+    async fn coalesces_replies_per_recipient() {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut outbox = Outbox::new("bot", rx);
+
+        tx.send((String::from("boss"), String::from("Joining: #a"))).unwrap();
+        tx.send((String::from("other"), String::from("Bot buffer set to 10"))).unwrap();
+        tx.send((String::from("boss"), String::from("Joined: #a"))).unwrap();
+
+        assert_eq!(outbox.next().await.unwrap(), "PRIVMSG bot :/w boss Joining: #a | Joined: #a");
+        assert_eq!(outbox.next().await.unwrap(), "PRIVMSG bot :/w other Bot buffer set to 10");
+
+        drop(tx);
+        assert!(outbox.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn leaves_room_for_the_whisper_command() {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut outbox = Outbox::new("bot", rx);
+
+        // Together they fit in 500 characters, but not once `/w boss ` is counted.
+        tx.send((String::from("boss"), "a".repeat(300))).unwrap();
+        tx.send((String::from("boss"), "b".repeat(190))).unwrap();
+
+        let sent = outbox.next().await.unwrap();
+
+        assert_eq!(sent, format!("PRIVMSG bot :/w boss {}", "a".repeat(300)));
+    }
+}
@@ -0,0 +1,243 @@
+use indicatif::ProgressIterator;
+use std::panic;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time;
+use tokio::sync::mpsc;
+use tokio::task;
+
+use crate::lib::{commands, config, error, message, metrics, seen, shard, sink, store, twitch};
+
+// A parsed message with the bot state it was received under.
+type Job = (message::Message, config::State);
+
+type Queue = (config::SinkKind, mpsc::Sender<Job>, Arc<metrics::Queue>);
+
+// How far a sink can fall behind before new messages for it are dropped rather than held in
+// memory. The database sink journals what it receives, so this only covers a stalled disk.
+const SINK_QUEUE: usize = 50_000;
+
+// How long a sink writer waits for messages before letting its sink flush.
+const IDLE_TICK: time::Duration = time::Duration::from_secs(1);
+
+// Messages flow from the IRC readers to the parser and on to one writer per sink, each on its
+// own task with a queue in between, so a slow disk or database only backs up its own queue and
+// never a socket. Connections that drop or are asked to reconnect are replaced
+// one at a time while the rest keep logging. Returns once the parser stops, after every queued
+// message has been written.
+pub async fn run(
+    config: config::Config,
+    state: Arc<Mutex<config::State>>,
+    sinks: Vec<Box<dyn sink::Sink>>,
+    writer: Option<store::Writer>,
+) -> Result<(), error::Error> {
+    let (raw_tx, raw_rx) = mpsc::unbounded_channel();
+    let (events_tx, mut events_rx) = mpsc::unbounded_channel();
+    let routes = config.sinks.clone();
+    let channels = config.channels.clone();
+    let shards = shard::Shards::new(config, raw_tx, events_tx);
+
+    // The first connection is opened up front, so whispers can be answered before any joins.
+    shards.open().await?;
+
+    let joins = tokio::spawn(join_channels(shards.clone(), channels));
+    let mut queues = Vec::new();
+    let mut writers = Vec::new();
+
+    for sink in sinks {
+        let (tx, rx) = mpsc::channel(SINK_QUEUE);
+        let queue = metrics::queue(sink.kind().name());
+
+        queues.push((sink.kind(), tx, Arc::clone(&queue)));
+        writers.push(task::spawn_blocking(move || write(sink, rx, &queue)));
+    }
+
+    let mut parser = tokio::spawn(parse(shards.clone(), state, writer, routes, raw_rx, queues));
+
+    // The parser only stops early when a command or writer fails.
+    let parsed = loop {
+        tokio::select! {
+            parsed = &mut parser => break join(parsed),
+            // Recoveries give up on their own once the connections are closed.
+            Some(event) = events_rx.recv() => drop(tokio::spawn(shards.clone().recover(event))),
+        }
+    };
+
+    joins.abort();
+    shards.close().await;
+
+    for writer in writers {
+        join(writer.await)?;
+    }
+
+    parsed
+}
+
+// Task panics, such as from the `shutdown` command, carry on as if the task had run inline.
+fn join<T>(result: Result<T, task::JoinError>) -> T {
+    result.unwrap_or_else(|e| panic::resume_unwind(e.into_panic()))
+}
+
+// Runs alongside the readers, so channels already joined are logged while the rest are
+// joining within the rate limit. A connection that fails to open is retried with exponential
+// backoff.
+async fn join_channels(shards: shard::Shards, channels: Vec<String>) {
+    let count = channels.len();
+
+    for channel in channels.iter().progress() {
+        let mut backoff = shard::MIN_BACKOFF;
+
+        while let Err(e) = shards.join(channel).await {
+            eprintln!("Unable to join {}, retrying in {}s: {}", channel, backoff.as_secs(), e);
+
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(shard::MAX_BACKOFF);
+        }
+    }
+
+    match count {
+        0 => println!("Bot is now logging 0 channels..."),
+        1 => println!("Bot is now logging 1 channel..."),
+        _ => println!("Bot is now logging {} channels...", count),
+    }
+}
+
+// Runs admin whispers one at a time, in the order they arrived, on a blocking thread, since
+// commands may sleep between joins.
+async fn run_commands(
+    shards: shard::Shards,
+    state: Arc<Mutex<config::State>>,
+    writer: Option<store::Writer>,
+    mut rx: mpsc::UnboundedReceiver<message::Message>,
+) -> Result<(), error::Error> {
+    while let Some(parsed_msg) = rx.recv().await {
+        let (shards, state, writer) = (shards.clone(), Arc::clone(&state), writer.clone());
+
+        join(
+            task::spawn_blocking(move || {
+                let bot_state = state.lock().expect("Unable to acquire bot state mutex");
+                let result = commands::parse_cmd(&shards, bot_state, writer.as_ref(), &parsed_msg);
+
+                metrics::publish(*state.lock().expect("Unable to acquire bot state mutex"));
+
+                result
+            })
+            .await,
+        )?;
+    }
+
+    Ok(())
+}
+
+// The next line to parse, unless a command failed or shut the bot down first.
+async fn next(
+    raw_rx: &mut mpsc::UnboundedReceiver<twitch::Message>,
+    commands: &mut task::JoinHandle<Result<(), error::Error>>,
+) -> Result<Option<twitch::Message>, error::Error> {
+    tokio::select! {
+        raw_msg = raw_rx.recv() => Ok(raw_msg),
+        ran = commands => join(ran).map(|_| None),
+    }
+}
+
+// Parses each line and routes it to the sinks for its channel. Admin whispers are handed to
+// their own task, so a slow command never holds up logging. Whispers arrive on every
+// connection, and channel messages on two while a connection is replaced, so each message id
+// is only handled the first time.
+async fn parse(
+    shards: shard::Shards,
+    state: Arc<Mutex<config::State>>,
+    writer: Option<store::Writer>,
+    routes: config::Sinks,
+    mut raw_rx: mpsc::UnboundedReceiver<twitch::Message>,
+    queues: Vec<Queue>,
+) -> Result<(), error::Error> {
+    let mut seen = seen::Seen::default();
+    let (commands_tx, commands_rx) = mpsc::unbounded_channel();
+    let mut commands = tokio::spawn(run_commands(shards, Arc::clone(&state), writer, commands_rx));
+
+    while let Some(raw_msg) = next(&mut raw_rx, &mut commands).await? {
+        metrics::RAW_QUEUE.fetch_sub(1, Ordering::Relaxed);
+
+        let channel = metrics::channel(&raw_msg).map(String::from);
+        let parsed_msg = message::Message::parse_msg(&raw_msg)?;
+
+        if !parsed_msg.msg_id.is_empty() && !seen.insert(&parsed_msg.seen_key()) {
+            continue;
+        }
+
+        let parsed = !parsed_msg.command.is_empty();
+        let mut logged = false;
+
+        if parsed_msg.command == "WHISPER" {
+            // Commands only stop taking whispers once they have failed, which `next` returns.
+            let _ = commands_tx.send(parsed_msg);
+        } else if parsed {
+            let bot_state = *state.lock().expect("Unable to acquire bot state mutex");
+
+            if !bot_state.paused {
+                let kinds = routes.for_channel(&parsed_msg.target);
+
+                for (_, tx, queue) in queues.iter().filter(|(kind, _, _)| kinds.contains(kind)) {
+                    queue.depth.fetch_add(1, Ordering::Relaxed);
+
+                    match tx.try_send((parsed_msg.clone(), bot_state)) {
+                        Ok(_) => logged = true,
+                        Err(mpsc::error::TrySendError::Full(_)) => {
+                            queue.depth.fetch_sub(1, Ordering::Relaxed);
+                            queue.dropped.fetch_add(1, Ordering::Relaxed);
+                        }
+                        // A writer only goes away after failing; its error is returned when
+                        // joined.
+                        Err(mpsc::error::TrySendError::Closed(_)) => return Ok(()),
+                    }
+                }
+            }
+        }
+
+        if let Some(channel) = channel {
+            metrics::message(&channel, parsed, logged);
+        }
+    }
+
+    // Whispers already handed over still run before the connections close.
+    drop(commands_tx);
+    join(commands.await)
+}
+
+// Everything queued since the last write goes to the sink in one call, up to one buffer's
+// worth, so a backlog drains in batches.
+fn write(
+    mut sink: Box<dyn sink::Sink>,
+    mut rx: mpsc::Receiver<Job>,
+    queue: &metrics::Queue,
+) -> Result<(), error::Error> {
+    let runtime = tokio::runtime::Handle::current();
+
+    loop {
+        let (log, mut state) = match runtime.block_on(tokio::time::timeout(IDLE_TICK, rx.recv())) {
+            Ok(Some(job)) => job,
+            Ok(None) => break,
+            Err(_) => {
+                sink.idle()?;
+                continue;
+            }
+        };
+        let mut logs = vec![log];
+
+        while logs.len() < state.buffer {
+            match rx.try_recv() {
+                Ok((log, latest)) => {
+                    logs.push(log);
+                    state = latest;
+                }
+                Err(_) => break,
+            }
+        }
+
+        queue.depth.fetch_sub(logs.len(), Ordering::Relaxed);
+        sink.write(&logs, &state)?;
+    }
+
+    Ok(())
+}
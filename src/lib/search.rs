@@ -0,0 +1,118 @@
+use chrono::prelude::*;
+
+use crate::lib::message;
+
+#[derive(Serialize, Debug)]
+pub struct Hit {
+    pub msg_id: String,
+    pub target: String,
+    pub username: String,
+    pub system_msg: String,
+    pub user_msg: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+impl Hit {
+    pub fn line(&self) -> String {
+        let timestamp = self.timestamp.format("%Y-%m-%d %H:%M:%S");
+
+        if self.system_msg.is_empty() {
+            format!("{} {} {}: {}", timestamp, self.target, self.username, self.user_msg)
+        } else {
+            format!(
+                "{} {} - [Notice] {} {}",
+                timestamp, self.target, self.system_msg, self.user_msg
+            )
+        }
+    }
+}
+
+// A deletion, timeout, ban or chat clear, as read back for display.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Moderation {
+    pub command: String,
+    pub username: String,
+    pub target_msg_id: String,
+    pub ban_duration: i32,
+    pub user_msg: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+impl Moderation {
+    pub fn describe(&self) -> String {
+        match (self.command.as_str(), self.username.is_empty(), self.ban_duration) {
+            ("CLEARMSG", _, _) => format!("A message from {} was deleted", self.username),
+            (_, true, _) => String::from("Chat was cleared"),
+            (_, false, 0) => format!("{} has been banned", self.username),
+            (_, false, duration) => {
+                format!("{} has been timed out for {} seconds", self.username, duration)
+            }
+        }
+    }
+}
+
+impl From<&message::Message> for Moderation {
+    fn from(log: &message::Message) -> Self {
+        Self {
+            command: log.command.clone(),
+            username: log.username.clone(),
+            target_msg_id: log.target_msg_id.clone(),
+            ban_duration: log.ban_duration,
+            user_msg: log.user_msg.clone(),
+            timestamp: log.timestamp,
+        }
+    }
+}
+
+// Filters shared by the `search` subcommand and whisper command. `since` and `until` are
+// whole UTC days, so `until` is stored as the start of the following day.
+#[derive(Debug, Default)]
+pub struct Query {
+    pub channel: Option<String>,
+    pub user: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub text: String,
+}
+
+// Days are queried up to the start of the next and linked to the one before, so both must
+// exist as well.
+pub fn parse_day(day: &str) -> Result<DateTime<Utc>, String> {
+    NaiveDate::parse_from_str(day, "%Y-%m-%d")
+        .ok()
+        .filter(|x| x.pred_opt().is_some() && x.succ_opt().is_some())
+        .and_then(|x| x.and_hms_opt(0, 0, 0))
+        .map(|x| Utc.from_utc_datetime(&x))
+        .ok_or_else(|| format!("Invalid date {}, expected YYYY-MM-DD", day))
+}
+
+impl Query {
+    // Arguments are `#channel`, `from:user`, `since:YYYY-MM-DD` and `until:YYYY-MM-DD` in any
+    // order; everything else is the text to match, where "quoted words" match as a phrase.
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let mut query = Self::default();
+        let mut text = Vec::new();
+
+        for arg in args {
+            if arg.starts_with('#') && arg.len() > 1 {
+                query.channel = Some(arg.to_lowercase());
+            } else if let Some(user) = arg.strip_prefix("from:") {
+                query.user = Some(user.trim_start_matches('@').to_lowercase());
+            } else if let Some(day) = arg.strip_prefix("since:") {
+                query.since = Some(parse_day(day)?);
+            } else if let Some(day) = arg.strip_prefix("until:") {
+                query.until = Some(parse_day(day)? + chrono::Duration::days(1));
+            } else {
+                text.push(arg.as_str());
+            }
+        }
+
+        query.text = text.join(" ");
+
+        if query.text.is_empty() && query.user.is_none() {
+            return Err(String::from("Search needs some text or a from:user filter"));
+        }
+
+        Ok(query)
+    }
+}
@@ -0,0 +1,37 @@
+use std::collections::{HashSet, VecDeque};
+use std::time;
+
+// Long enough to cover the messages Twitch replays to a connection that has just rejoined.
+const SEEN_TTL: time::Duration = time::Duration::from_secs(10 * 60);
+
+// Message ids handled recently, for messages that arrive on more than one connection.
+#[derive(Debug, Default)]
+pub struct Seen {
+    ids: HashSet<String>,
+    order: VecDeque<(time::Instant, String)>,
+}
+
+impl Seen {
+    // Returns false if the id was already seen within the last `SEEN_TTL`.
+    pub fn insert(&mut self, id: &str) -> bool {
+        let now = time::Instant::now();
+
+        while let Some((seen_at, _)) = self.order.front() {
+            if now.duration_since(*seen_at) < SEEN_TTL {
+                break;
+            }
+
+            if let Some((_, expired)) = self.order.pop_front() {
+                self.ids.remove(&expired);
+            }
+        }
+
+        if !self.ids.insert(id.to_string()) {
+            return false;
+        }
+
+        self.order.push_back((now, id.to_string()));
+
+        true
+    }
+}
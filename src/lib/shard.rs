@@ -0,0 +1,577 @@
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time;
+use tokio::runtime;
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio::task;
+
+use crate::lib::{bucket, config, error, health, metrics, outbox, twitch};
+
+// Twitch counts joins per account over 10 seconds, across every connection.
+const JOIN_WINDOW: time::Duration = time::Duration::from_secs(10);
+// Queued joins report progress to the admin every so many channels.
+const JOIN_PROGRESS: usize = 100;
+// How long a queued join waits for Twitch to confirm or refuse it.
+const JOIN_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+// NOTICEs Twitch sends instead of echoing a join.
+const JOIN_REFUSALS: &[&str] = &["msg_channel_suspended", "msg_banned", "tos_ban"];
+pub const MIN_BACKOFF: time::Duration = time::Duration::from_secs(1);
+pub const MAX_BACKOFF: time::Duration = time::Duration::from_secs(5 * 60);
+
+// A connection asking to be replaced: `None` when Twitch sent a `RECONNECT`, or the error that
+// dropped it.
+pub type Event = (u64, Option<error::Error>);
+
+// Twitch's answer to a join: its echo, or the text of the NOTICE refusing it.
+type Joined = Result<(), String>;
+type Waiting = Arc<Mutex<HashMap<String, oneshot::Sender<Joined>>>>;
+
+struct Shard {
+    id: u64,
+    client: twitch::Client,
+    channels: Vec<String>,
+    reader: task::JoinHandle<()>,
+    replacing: bool,
+}
+
+struct Inner {
+    shards: Vec<Shard>,
+    next_id: u64,
+    // Channels queued by `queue_join` and not yet joined.
+    pending: Vec<String>,
+    // Replacements not yet swapped in, with the event each raised in the meantime, if any.
+    starting: HashMap<u64, Option<Event>>,
+    // Taken on close, so the parser sees the end of the queue once every reader has stopped.
+    raw_tx: Option<mpsc::UnboundedSender<twitch::Message>>,
+}
+
+impl Inner {
+    // An id and queue for a new connection, unless connections are closed.
+    fn reserve(&mut self) -> Result<(u64, mpsc::UnboundedSender<twitch::Message>), error::Error> {
+        let Some(raw_tx) = self.raw_tx.clone() else {
+            return Err(error::Error::Irc(String::from("Connections to Twitch are closed")));
+        };
+
+        self.next_id += 1;
+
+        Ok((self.next_id, raw_tx))
+    }
+
+    // Open connections, and those not waiting to be replaced, for metrics and health checks.
+    fn export(&self) {
+        let up = self.shards.iter().filter(|x| !x.replacing).count();
+
+        metrics::CONNECTIONS.store(self.shards.len(), Ordering::Relaxed);
+        health::connections(up, self.shards.len());
+    }
+}
+
+// Channels spread over as many connections as `channels_per_connection` requires. Every
+// connection feeds the same queue, so all of them are logged by the same sinks.
+#[derive(Clone)]
+pub struct Shards {
+    inner: Arc<Mutex<Inner>>,
+    events_tx: mpsc::UnboundedSender<Event>,
+    replies_tx: mpsc::UnboundedSender<(String, String)>,
+    joins: Arc<Mutex<bucket::Bucket>>,
+    // Channels queued by `queue_join` whose JOIN was sent and not yet answered.
+    waiting: Waiting,
+    config: Arc<config::Config>,
+    limit: usize,
+    runtime: runtime::Handle,
+}
+
+impl Shards {
+    // `events_tx` receives each connection that needs replacing, to be passed to `recover`.
+    pub fn new(
+        config: config::Config,
+        raw_tx: mpsc::UnboundedSender<twitch::Message>,
+        events_tx: mpsc::UnboundedSender<Event>,
+    ) -> Self {
+        let limit = if config.channels_per_connection == 0 {
+            usize::MAX
+        } else {
+            config.channels_per_connection
+        };
+
+        let (replies_tx, replies_rx) = mpsc::unbounded_channel();
+        let inner = Arc::new(Mutex::new(Inner {
+            shards: Vec::new(),
+            next_id: 0,
+            pending: Vec::new(),
+            starting: HashMap::new(),
+            raw_tx: Some(raw_tx),
+        }));
+
+        // Runs until every clone is dropped and the last replies are sent.
+        tokio::spawn(deliver(
+            Arc::clone(&inner),
+            outbox::Outbox::new(&config.nickname, replies_rx),
+        ));
+
+        Self {
+            inner,
+            events_tx,
+            replies_tx,
+            joins: Arc::new(Mutex::new(bucket::Bucket::new(config.join_limit, JOIN_WINDOW))),
+            waiting: Arc::new(Mutex::new(HashMap::new())),
+            config: Arc::new(config),
+            limit,
+            runtime: runtime::Handle::current(),
+        }
+    }
+
+    // Connects, logs in and starts queueing lines from the new connection.
+    pub async fn open(&self) -> Result<(), error::Error> {
+        let mut inner = self.inner.lock().await;
+
+        self.add(&mut inner).await
+    }
+
+    async fn add(&self, inner: &mut Inner) -> Result<(), error::Error> {
+        let (id, raw_tx) = inner.reserve()?;
+        let shard = self.connect(id, raw_tx).await?;
+
+        inner.shards.push(shard);
+        inner.export();
+
+        Ok(())
+    }
+
+    async fn connect(
+        &self,
+        id: u64,
+        raw_tx: mpsc::UnboundedSender<twitch::Message>,
+    ) -> Result<Shard, error::Error> {
+        let config = &self.config;
+        let (client, mut reader) =
+            twitch::connect(&config.server, config.port(), config.tls).await?;
+
+        client.send(&format!("PASS {}", config.oauth))?;
+        client.send(&format!("NICK {}", config.nickname))?;
+        client.send("CAP REQ :twitch.tv/tags")?;
+        client.send("CAP REQ :twitch.tv/commands")?;
+
+        // Lines are still read after a `RECONNECT`, until the replacement has joined.
+        let events_tx = self.events_tx.clone();
+        let waiting = Arc::clone(&self.waiting);
+        let nickname = config.nickname.clone();
+        let reader = tokio::spawn(async move {
+            loop {
+                match reader.next().await {
+                    Ok(raw_msg) => {
+                        health::message();
+
+                        if let Some((channel, joined)) = join_reply(&raw_msg, &nickname) {
+                            if let Some(tx) = waiting.lock().await.remove(&channel) {
+                                let _ = tx.send(joined);
+                            }
+                        }
+
+                        if raw_msg.command == "RECONNECT" {
+                            let _ = events_tx.send((id, None));
+                        }
+
+                        metrics::RAW_QUEUE.fetch_add(1, Ordering::Relaxed);
+
+                        if raw_tx.send(raw_msg).is_err() {
+                            metrics::RAW_QUEUE.fetch_sub(1, Ordering::Relaxed);
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        let _ = events_tx.send((id, Some(e)));
+                        break;
+                    }
+                }
+            }
+        });
+
+        Ok(Shard { id, client, channels: Vec::new(), reader, replacing: false })
+    }
+
+    // Replaces a connection with a new one, retrying with exponential backoff until it has
+    // joined the old one's channels. The old connection is closed last, so nothing is missed
+    // after a `RECONNECT`; the parser skips messages seen on both.
+    pub async fn recover(self, (id, error): Event) {
+        {
+            let mut inner = self.inner.lock().await;
+
+            // A replacement that fails before it is swapped in is recovered once it is.
+            if let Some(held) = inner.starting.get_mut(&id) {
+                held.get_or_insert((id, error));
+                return;
+            }
+
+            match inner.shards.iter_mut().find(|x| x.id == id) {
+                Some(shard) if !shard.replacing => shard.replacing = true,
+                _ => return,
+            }
+
+            inner.export();
+        }
+
+        match error {
+            Some(e) => eprintln!("Connection to Twitch dropped, reconnecting: {}", e),
+            None => println!("Reconnecting at Twitch's request..."),
+        }
+
+        metrics::RECONNECTS.fetch_add(1, Ordering::Relaxed);
+
+        let mut backoff = MIN_BACKOFF;
+
+        while let Err(e) = self.replace(id).await {
+            eprintln!("Unable to reconnect, retrying in {}s: {}", backoff.as_secs(), e);
+
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    // Gives up quietly once the old connection is gone or all are closed. The new connection
+    // can drop or be asked to reconnect while it is joining, so its event is held until the
+    // swap and then sent again.
+    async fn replace(&self, id: u64) -> Result<(), error::Error> {
+        let (channels, new_id, raw_tx) = {
+            let mut inner = self.inner.lock().await;
+            let Some(shard) = inner.shards.iter().find(|x| x.id == id) else {
+                return Ok(());
+            };
+            let channels = shard.channels.clone();
+            let Ok((new_id, raw_tx)) = inner.reserve() else {
+                return Ok(());
+            };
+
+            inner.starting.insert(new_id, None);
+            (channels, new_id, raw_tx)
+        };
+
+        let swapped = self.swap_in(id, new_id, channels, raw_tx).await;
+
+        if let Some(event) = self.inner.lock().await.starting.remove(&new_id).flatten() {
+            let _ = self.events_tx.send(event);
+        }
+
+        swapped
+    }
+
+    // Channels joined or parted while the new connection was joining are caught up on it
+    // before the swap.
+    async fn swap_in(
+        &self,
+        id: u64,
+        new_id: u64,
+        channels: Vec<String>,
+        raw_tx: mpsc::UnboundedSender<twitch::Message>,
+    ) -> Result<(), error::Error> {
+        let mut shard = self.connect(new_id, raw_tx).await?;
+        let joined = async {
+            for channel in &channels {
+                self.wait_join().await;
+                shard.client.send_join(channel)?;
+            }
+
+            Ok::<(), error::Error>(())
+        };
+
+        if let Err(e) = joined.await {
+            shard.reader.abort();
+            return Err(e);
+        }
+
+        let mut inner = self.inner.lock().await;
+        let Some(i) = inner.shards.iter().position(|x| x.id == id) else {
+            shard.reader.abort();
+            return Ok(());
+        };
+
+        let current = inner.shards[i].channels.clone();
+        let caught_up = async {
+            for channel in current.iter().filter(|x| !channels.contains(x)) {
+                self.wait_join().await;
+                shard.client.send_join(channel)?;
+            }
+
+            channels
+                .iter()
+                .filter(|x| !current.contains(x))
+                .try_for_each(|x| shard.client.send_part(x))
+        };
+
+        if let Err(e) = caught_up.await {
+            shard.reader.abort();
+            return Err(e);
+        }
+
+        shard.channels = std::mem::take(&mut inner.shards[i].channels);
+        std::mem::replace(&mut inner.shards[i], shard).reader.abort();
+        inner.export();
+
+        Ok(())
+    }
+
+    async fn wait_join(&self) {
+        self.joins.lock().await.wait().await;
+    }
+
+    // Joins through the least loaded connection with room, opening one when all are full. Waits
+    // for the join rate limit first.
+    pub async fn join(&self, channel: &str) -> Result<(), error::Error> {
+        self.wait_join().await;
+
+        let mut inner = self.inner.lock().await;
+
+        self.join_with(&mut inner, channel).await
+    }
+
+    // Joins a channel queued by `queue_join`, unless it was parted while waiting, and returns
+    // where Twitch's answer will arrive. A channel that is already joined is answered at once.
+    async fn join_queued(
+        &self,
+        channel: &str,
+    ) -> Result<Option<oneshot::Receiver<Joined>>, error::Error> {
+        self.wait_join().await;
+
+        let mut inner = self.inner.lock().await;
+
+        if !inner.pending.iter().any(|x| x == channel) {
+            return Ok(None);
+        }
+
+        let (tx, rx) = oneshot::channel();
+
+        if inner.shards.iter().any(|x| x.channels.iter().any(|name| name == channel)) {
+            let _ = tx.send(Ok(()));
+        } else {
+            self.waiting.lock().await.insert(channel.to_string(), tx);
+
+            if let Err(e) = self.join_with(&mut inner, channel).await {
+                self.waiting.lock().await.remove(channel);
+                return Err(e);
+            }
+        }
+
+        inner.pending.retain(|x| x != channel);
+
+        Ok(Some(rx))
+    }
+
+    async fn join_with(&self, inner: &mut Inner, channel: &str) -> Result<(), error::Error> {
+        if inner.shards.iter().any(|x| x.channels.iter().any(|name| name == channel)) {
+            return Ok(());
+        }
+
+        if self.with_room(inner, None).is_none() {
+            self.add(inner).await?;
+        }
+
+        let i = self.with_room(inner, None).unwrap_or(inner.shards.len() - 1);
+        let shard = &mut inner.shards[i];
+
+        shard.client.send_join(channel)?;
+        shard.channels.push(channel.to_string());
+
+        Ok(())
+    }
+
+    // Joins the channels in the background, within the rate limit, and whispers progress to
+    // `admin`. A join that fails to send is reported and retried with exponential backoff. Once
+    // all are sent, the channels Twitch confirmed and those it refused or never answered are
+    // reported.
+    pub fn queue_join(&self, channels: Vec<String>, admin: &str) {
+        self.runtime.block_on(self.inner.lock()).pending.extend(channels.iter().cloned());
+        self.runtime.spawn(self.clone().join_pending(channels, admin.to_string()));
+    }
+
+    async fn join_pending(self, channels: Vec<String>, admin: String) {
+        let mut answers = Vec::new();
+
+        for (i, channel) in channels.iter().enumerate() {
+            let mut backoff = MIN_BACKOFF;
+
+            loop {
+                match self.join_queued(channel).await {
+                    Ok(Some(rx)) => answers.push((channel, tokio::spawn(confirm(rx)))),
+                    Ok(None) => {}
+                    Err(e) => {
+                        eprintln!("Unable to join {}: {}", channel, e);
+                        self.reply(
+                            &admin,
+                            &format!(
+                                "Unable to join {}, retrying in {}s: {}",
+                                channel,
+                                backoff.as_secs(),
+                                e
+                            ),
+                        );
+
+                        tokio::time::sleep(backoff).await;
+                        backoff = (backoff * 2).min(MAX_BACKOFF);
+                        continue;
+                    }
+                }
+
+                break;
+            }
+
+            if (i + 1) % JOIN_PROGRESS == 0 && i + 1 < channels.len() {
+                self.reply(&admin, &format!("Joined {} of {} channels", i + 1, channels.len()));
+            }
+        }
+
+        let mut joined = Vec::new();
+        let mut failed = Vec::new();
+
+        for (channel, answer) in answers {
+            match answer.await {
+                Ok(Ok(_)) => joined.push(channel.as_str()),
+                Ok(Err(reason)) => {
+                    self.waiting.lock().await.remove(channel);
+                    failed.push(format!("{} ({})", channel, reason));
+                }
+                Err(e) => failed.push(format!("{} ({})", channel, e)),
+            }
+        }
+
+        if !joined.is_empty() {
+            self.reply(&admin, &format!("Joined: {}", joined.join(" ")));
+        }
+
+        if !failed.is_empty() {
+            eprintln!("Unable to join: {}", failed.join(", "));
+            self.reply(&admin, &format!("Unable to join: {}", failed.join(", ")));
+        }
+    }
+
+    // Replies from background tasks have nowhere to report a closed queue, so it is logged.
+    fn reply(&self, admin: &str, reply: &str) {
+        if let Err(e) = self.whisper(admin, reply) {
+            eprintln!("{}", e);
+        }
+    }
+
+    // Parts the channel, then closes the emptiest connection while the rest can take its
+    // channels. Moved channels are queued like `queue_join`, and the old connection closes once
+    // they are joined on the others; the parser skips messages seen on both.
+    pub async fn part(&self, channel: &str, admin: &str) -> Result<(), error::Error> {
+        let mut inner = self.inner.lock().await;
+
+        inner.pending.retain(|x| x != channel);
+
+        for shard in &mut inner.shards {
+            if let Some(i) = shard.channels.iter().position(|x| x == channel) {
+                shard.client.send_part(channel)?;
+                shard.channels.remove(i);
+            }
+        }
+
+        let total: usize = inner.shards.iter().map(|x| x.channels.len()).sum();
+        let needed = total.div_ceil(self.limit).max(1);
+        let mut moved = Vec::new();
+        let mut closing = Vec::new();
+
+        while inner.shards.len() > needed {
+            let emptiest = (0..inner.shards.len())
+                .min_by_key(|i| inner.shards[*i].channels.len())
+                .unwrap_or(0);
+            let mut shard = inner.shards.remove(emptiest);
+
+            moved.append(&mut shard.channels);
+            closing.push(shard);
+        }
+
+        inner.pending.extend(moved.iter().cloned());
+        inner.export();
+
+        let (shards, admin) = (self.clone(), admin.to_string());
+
+        tokio::spawn(async move {
+            shards.join_pending(moved, admin).await;
+
+            for shard in closing {
+                shard.reader.abort();
+            }
+        });
+
+        Ok(())
+    }
+
+    fn with_room(&self, inner: &Inner, except: Option<usize>) -> Option<usize> {
+        (0..inner.shards.len())
+            .filter(|i| Some(*i) != except && inner.shards[*i].channels.len() < self.limit)
+            .min_by_key(|i| inner.shards[*i].channels.len())
+    }
+
+    // Stops every connection and drops queued joins; lines already queued are still parsed.
+    pub async fn close(&self) {
+        let mut inner = self.inner.lock().await;
+
+        for shard in inner.shards.drain(..) {
+            shard.reader.abort();
+        }
+
+        inner.pending.clear();
+        inner.raw_tx = None;
+        self.waiting.lock().await.clear();
+        inner.export();
+    }
+
+    // Queues a whisper reply, sent through the first connection within the whisper limits.
+    pub fn whisper(&self, admin: &str, reply: &str) -> Result<(), error::Error> {
+        self.replies_tx
+            .send((admin.to_string(), reply.to_string()))
+            .map_err(|_| error::Error::Irc(String::from("Connections to Twitch are closed")))
+    }
+
+    pub fn send_part(&self, channel: &str, admin: &str) -> Result<(), error::Error> {
+        self.runtime.block_on(self.part(channel, admin))
+    }
+}
+
+// Twitch's answer to a join, or an error once it has taken longer than `JOIN_TIMEOUT`.
+async fn confirm(rx: oneshot::Receiver<Joined>) -> Joined {
+    match tokio::time::timeout(JOIN_TIMEOUT, rx).await {
+        Ok(Ok(joined)) => joined,
+        _ => Err(String::from("no answer from Twitch")),
+    }
+}
+
+// The channel and outcome of a line answering one of our joins: our own JOIN echoed back, or a
+// NOTICE refusing it.
+fn join_reply(raw_msg: &twitch::Message, nickname: &str) -> Option<(String, Joined)> {
+    let channel = raw_msg.args.first()?.clone();
+
+    match raw_msg.command.as_str() {
+        "JOIN" => {
+            let user = raw_msg.prefix.as_deref()?.split('!').next()?;
+
+            user.eq_ignore_ascii_case(nickname).then(|| (channel, Ok(())))
+        }
+        "NOTICE" => {
+            let refused = raw_msg
+                .tags
+                .iter()
+                .any(|(key, value)| key == "msg-id" && JOIN_REFUSALS.contains(&value.as_str()));
+
+            refused.then(|| (channel, Err(raw_msg.trailing.clone().unwrap_or_default())))
+        }
+        _ => None,
+    }
+}
+
+// Sends queued replies through the first connection. Replies sent while no connection is open
+// are logged and dropped.
+async fn deliver(inner: Arc<Mutex<Inner>>, mut outbox: outbox::Outbox) {
+    while let Some(line) = outbox.next().await {
+        let inner = inner.lock().await;
+        let sent = inner.shards.first().map_or_else(
+            || Err(error::Error::Irc(String::from("Not connected to Twitch"))),
+            |shard| shard.client.send(&line),
+        );
+
+        if let Err(e) = sent {
+            eprintln!("Unable to send reply: {}", e);
+        }
+    }
+}
@@ -0,0 +1,123 @@
+use std::mem;
+
+use crate::lib::{config, error, file, index, journal, message, store};
+
+// An output for logged channel messages. Each sink runs on its own writer task, which hands it
+// the messages routed to it by `config.sinks` and leaves buffering up to the sink.
+pub trait Sink: Send {
+    fn kind(&self) -> config::SinkKind;
+    fn write(
+        &mut self,
+        logs: &[message::Message],
+        state: &config::State,
+    ) -> Result<(), error::Error>;
+    // Called when no messages have arrived for a while, so buffered work is not held forever.
+    fn idle(&mut self) -> Result<(), error::Error> {
+        Ok(())
+    }
+}
+
+pub struct Text {
+    templates: config::Templates,
+    index: Option<index::Index>,
+}
+
+impl Sink for Text {
+    fn kind(&self) -> config::SinkKind {
+        config::SinkKind::Text
+    }
+
+    fn write(
+        &mut self,
+        logs: &[message::Message],
+        state: &config::State,
+    ) -> Result<(), error::Error> {
+        for log in logs {
+            let (path, offset) = file::Logger::save_msg_txt(log, state.buffer, &self.templates)?;
+
+            if log.is_moderation() {
+                file::save_moderation(log)?;
+            } else if let Some(index) = &mut self.index {
+                index.add(log, &path, offset)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn idle(&mut self) -> Result<(), error::Error> {
+        match &mut self.index {
+            Some(index) => index.tick(),
+            None => Ok(()),
+        }
+    }
+}
+
+// Journals every message and hands batches of `state.buffer` rows to the database writer.
+pub struct Database {
+    writer: store::Writer,
+    journal: journal::Journal,
+    batch: Vec<message::Message>,
+}
+
+impl Sink for Database {
+    fn kind(&self) -> config::SinkKind {
+        config::SinkKind::Database
+    }
+
+    fn write(
+        &mut self,
+        logs: &[message::Message],
+        state: &config::State,
+    ) -> Result<(), error::Error> {
+        for log in logs {
+            self.journal.append(log)?;
+            self.batch.push(log.to_owned());
+        }
+
+        if self.batch.len() >= state.buffer {
+            self.flush()?;
+        }
+
+        Ok(())
+    }
+
+    // A partial batch on a quiet channel would otherwise wait for `state.buffer` rows.
+    fn idle(&mut self) -> Result<(), error::Error> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+
+        self.flush()
+    }
+}
+
+impl Database {
+    fn flush(&mut self) -> Result<(), error::Error> {
+        let segment = self.journal.seal()?;
+
+        self.writer.insert_logs(mem::take(&mut self.batch), segment)
+    }
+}
+
+// Builds one sink of each kind that can run; the database sink is left out when no store is
+// enabled, so channels routed to it only get the remaining sinks. Without a database, text
+// logs are indexed instead so they can still be searched.
+pub fn open(
+    config: &config::Config,
+    writer: Option<store::Writer>,
+) -> Result<Vec<Box<dyn Sink>>, error::Error> {
+    let index = if writer.is_none() { Some(index::Index::open()?) } else { None };
+    let mut sinks: Vec<Box<dyn Sink>> =
+        vec![Box::new(Text { templates: config.templates.clone(), index })];
+
+    if let Some(writer) = writer {
+        sinks.push(Box::new(Database {
+            writer,
+            journal: journal::Journal::open()?,
+            batch: Vec::new(),
+        }));
+    }
+
+    Ok(sinks)
+}
@@ -0,0 +1,327 @@
+use chrono::prelude::*;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::path;
+use std::sync::Mutex;
+
+use crate::lib::{error, message, search, store};
+
+// Migrations are tracked in `schema_version` like the Postgres ones, but numbered separately
+// since the schemas diverge.
+const MIGRATIONS: &[(i32, &str)] = &[
+    (1, include_str!("../../migrations/sqlite/0001_create_tables.sql")),
+    (2, include_str!("../../migrations/sqlite/0002_moderation.sql")),
+];
+
+const CHANLOG_COLUMNS: &str = "msg_id, command, target, room_id, user_id, user_type, username, sub_count, system_msg, user_msg, timestamp";
+
+const HIT_COLUMNS: &str = "COALESCE(msg_id, ''), target, username, COALESCE(system_msg, ''),
+    COALESCE(user_msg, ''), timestamp";
+
+fn hit(row: &rusqlite::Row) -> rusqlite::Result<search::Hit> {
+    Ok(search::Hit {
+        msg_id: row.get(0)?,
+        target: row.get(1)?,
+        username: row.get(2)?,
+        system_msg: row.get(3)?,
+        user_msg: row.get(4)?,
+        timestamp: row.get(5)?,
+    })
+}
+
+// Writes and queries each get their own connection, so that with WAL a long search from the
+// API never holds up the writer thread, nor the other way around.
+#[derive(Debug)]
+pub struct Sqlite {
+    conn: Mutex<Connection>,
+    reader: Mutex<Connection>,
+}
+
+impl Sqlite {
+    pub fn open(file: &str) -> Result<Self, error::Error> {
+        if let Some(parent) = path::Path::new(file).parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let conn = Connection::open(file)?;
+
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+            PRAGMA foreign_keys = ON;
+            PRAGMA busy_timeout = 5000;",
+        )?;
+
+        let reader = Connection::open(file)?;
+
+        reader.execute_batch(
+            "PRAGMA query_only = ON;
+            PRAGMA busy_timeout = 5000;",
+        )?;
+
+        Ok(Self { conn: Mutex::new(conn), reader: Mutex::new(reader) })
+    }
+
+    fn conn(&self) -> std::sync::MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    fn reader(&self) -> std::sync::MutexGuard<'_, Connection> {
+        self.reader.lock().unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+}
+
+fn upsert_entities(
+    transaction: &rusqlite::Transaction,
+    logs: &[message::Message],
+) -> Result<(), error::Error> {
+    let entities = store::entities(logs);
+
+    for (table, key, name, entities) in &[
+        ("channels", "room_id", "name", &entities.channels),
+        ("users", "user_id", "username", &entities.users),
+    ] {
+        let mut statement = transaction.prepare(&format!(
+            "INSERT INTO {table} ({key}, {name}, first_seen, last_seen) VALUES (?1, ?2, ?3, ?4)
+                ON CONFLICT ({key}) DO UPDATE SET
+                    {name} = CASE WHEN excluded.last_seen >= {table}.last_seen
+                        THEN excluded.{name} ELSE {table}.{name} END,
+                    first_seen = min({table}.first_seen, excluded.first_seen),
+                    last_seen = max({table}.last_seen, excluded.last_seen)",
+            table = table,
+            key = key,
+            name = name
+        ))?;
+
+        for (id, (name, first_seen, last_seen)) in *entities {
+            statement.execute(params![id, name, first_seen, last_seen])?;
+        }
+    }
+
+    let mut statement = transaction.prepare(
+        "INSERT INTO user_names (user_id, login, display_name, first_seen, last_seen)
+            VALUES (?1, ?2, ?3, ?4, ?5)
+            ON CONFLICT (user_id, login, display_name) DO UPDATE SET
+                first_seen = min(user_names.first_seen, excluded.first_seen),
+                last_seen = max(user_names.last_seen, excluded.last_seen)",
+    )?;
+
+    for ((user_id, login, display_name), (first_seen, last_seen)) in &entities.names {
+        statement.execute(params![user_id, login, display_name, first_seen, last_seen])?;
+    }
+
+    Ok(())
+}
+
+impl store::Store for Sqlite {
+    fn migrate(&self) -> Result<(), error::Error> {
+        let mut conn = self.conn();
+
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS schema_version (
+                version INT PRIMARY KEY,
+                applied_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+            );",
+        )?;
+
+        let current: i32 = conn
+            .query_row("SELECT MAX(version) FROM schema_version", [], |row| row.get(0))
+            .optional()?
+            .flatten()
+            .unwrap_or(0);
+
+        for (version, sql) in MIGRATIONS.iter().filter(|(version, _)| *version > current) {
+            let transaction = conn.transaction()?;
+
+            transaction.execute_batch(sql)?;
+            transaction.execute("INSERT INTO schema_version (version) VALUES (?1)", [version])?;
+            transaction.commit()?;
+
+            println!("Applied database migration {}...", version);
+        }
+
+        Ok(())
+    }
+
+    fn write_logs(&self, logs: &[message::Message]) -> Result<(), error::Error> {
+        let (logs, moderation) = store::split(logs);
+        let mut conn = self.conn();
+        let transaction = conn.transaction()?;
+
+        upsert_entities(&transaction, &logs)?;
+
+        {
+            let mut statement = transaction.prepare(&format!(
+                "INSERT INTO chanlog ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)
+                    ON CONFLICT DO NOTHING",
+                CHANLOG_COLUMNS
+            ))?;
+
+            for log in &logs {
+                statement.execute(params![
+                    store::msg_id(log),
+                    log.command,
+                    log.target,
+                    store::room_id(log),
+                    log.user_id,
+                    log.user_type.to_string(),
+                    log.username,
+                    log.sub_count,
+                    log.system_msg,
+                    log.user_msg,
+                    log.timestamp,
+                ])?;
+            }
+
+            let mut statement = transaction.prepare(
+                "INSERT INTO moderation (command, target, room_id, user_id, username, target_msg_id,
+                    ban_duration, user_msg, timestamp) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+                    ON CONFLICT DO NOTHING",
+            )?;
+
+            for log in &moderation {
+                statement.execute(params![
+                    log.command,
+                    log.target,
+                    store::room_id(log),
+                    store::user_id(log),
+                    log.username,
+                    store::target_msg_id(log),
+                    log.ban_duration,
+                    log.user_msg,
+                    log.timestamp,
+                ])?;
+            }
+        }
+
+        transaction.commit()?;
+
+        Ok(())
+    }
+
+    fn write_admin_logs(&self, logs: &[message::Message]) -> Result<(), error::Error> {
+        let mut conn = self.conn();
+        let transaction = conn.transaction()?;
+
+        for log in logs {
+            transaction.execute(
+                "INSERT INTO adminlog (user_id, username, user_msg, timestamp) VALUES (?1, ?2, ?3, ?4)",
+                params![log.user_id, log.username, log.user_msg, log.timestamp],
+            )?;
+        }
+
+        transaction.commit()?;
+
+        Ok(())
+    }
+
+    fn aliases(&self, login: &str) -> Result<Vec<store::Alias>, error::Error> {
+        let conn = self.reader();
+        let mut statement = conn.prepare(
+            "SELECT login, display_name, first_seen, last_seen FROM user_names
+                WHERE user_id IN (SELECT user_id FROM user_names WHERE login = ?1)
+                ORDER BY first_seen",
+        )?;
+
+        let rows = statement.query_map([login], |row| {
+            Ok(store::Alias {
+                login: row.get(0)?,
+                display_name: row.get(1)?,
+                first_seen: row.get(2)?,
+                last_seen: row.get(3)?,
+            })
+        })?;
+
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+    }
+
+    // There is no full-text index here, so the text matches as a case-insensitive substring.
+    fn search(&self, query: &search::Query, limit: u32) -> Result<Vec<search::Hit>, error::Error> {
+        let conn = self.reader();
+        let mut statement = conn.prepare(&format!(
+            "SELECT {} FROM chanlog
+                WHERE (?1 = '' OR instr(lower(user_msg), lower(?1)) > 0)
+                    AND (?2 IS NULL OR target = ?2)
+                    AND (?3 IS NULL OR username = ?3)
+                    AND (?4 IS NULL OR timestamp >= ?4)
+                    AND (?5 IS NULL OR timestamp < ?5)
+                ORDER BY timestamp DESC
+                LIMIT ?6",
+            HIT_COLUMNS
+        ))?;
+
+        let rows = statement.query_map(
+            params![query.text, query.channel, query.user, query.since, query.until, limit],
+            hit,
+        )?;
+
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+    }
+
+    fn channels(&self) -> Result<Vec<String>, error::Error> {
+        let conn = self.reader();
+        let mut statement = conn.prepare("SELECT name FROM channels ORDER BY name")?;
+        let rows = statement.query_map([], |row| row.get(0))?;
+
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+    }
+
+    fn logs(
+        &self,
+        channel: &str,
+        since: DateTime<Utc>,
+        until: DateTime<Utc>,
+    ) -> Result<Vec<search::Hit>, error::Error> {
+        let conn = self.reader();
+        let mut statement = conn.prepare(&format!(
+            "SELECT {} FROM chanlog
+                WHERE target = ?1 AND timestamp >= ?2 AND timestamp < ?3
+                ORDER BY timestamp",
+            HIT_COLUMNS
+        ))?;
+
+        let rows = statement.query_map(params![channel, since, until], hit)?;
+
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+    }
+
+    fn moderation(
+        &self,
+        channel: &str,
+        since: DateTime<Utc>,
+        until: DateTime<Utc>,
+    ) -> Result<Vec<search::Moderation>, error::Error> {
+        let conn = self.reader();
+        let mut statement = conn.prepare(
+            "SELECT command, username, COALESCE(target_msg_id, ''), ban_duration, user_msg, timestamp
+                FROM moderation
+                WHERE target = ?1 AND timestamp >= ?2 AND timestamp < ?3
+                ORDER BY timestamp",
+        )?;
+
+        let rows = statement.query_map(params![channel, since, until], |row| {
+            Ok(search::Moderation {
+                command: row.get(0)?,
+                username: row.get(1)?,
+                target_msg_id: row.get(2)?,
+                ban_duration: row.get(3)?,
+                user_msg: row.get(4)?,
+                timestamp: row.get(5)?,
+            })
+        })?;
+
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+    }
+
+    // Timestamps are stored as ISO 8601 text in UTC, so the day is their first ten characters.
+    fn days(&self, channel: &str, user: Option<&str>) -> Result<Vec<NaiveDate>, error::Error> {
+        let conn = self.reader();
+        let mut statement = conn.prepare(
+            "SELECT DISTINCT substr(timestamp, 1, 10) AS day FROM chanlog
+                WHERE target = ?1 AND (?2 IS NULL OR username = ?2)
+                ORDER BY day DESC",
+        )?;
+
+        let rows = statement.query_map(params![channel, user], |row| row.get(0))?;
+
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+    }
+}
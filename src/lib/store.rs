@@ -0,0 +1,336 @@
+use chrono::prelude::*;
+use std::collections::{BTreeMap, HashMap};
+use std::path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::{thread, time};
+
+use crate::lib::{config, db, error, health, journal, message, metrics, search, sqlite};
+
+#[derive(Debug)]
+pub struct Alias {
+    pub login: String,
+    pub display_name: String,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+}
+
+// A structured backend for `chanlog` and `adminlog`. Implementations are shared between the
+// writer thread and command handlers, so they manage their own connections.
+pub trait Store: Send + Sync {
+    fn migrate(&self) -> Result<(), error::Error>;
+    fn write_logs(&self, logs: &[message::Message]) -> Result<(), error::Error>;
+    fn write_admin_logs(&self, logs: &[message::Message]) -> Result<(), error::Error>;
+    fn aliases(&self, login: &str) -> Result<Vec<Alias>, error::Error>;
+    fn search(&self, query: &search::Query, limit: u32) -> Result<Vec<search::Hit>, error::Error>;
+    fn channels(&self) -> Result<Vec<String>, error::Error>;
+    // Every message logged to `channel` in [since, until), oldest first.
+    fn logs(
+        &self,
+        channel: &str,
+        since: DateTime<Utc>,
+        until: DateTime<Utc>,
+    ) -> Result<Vec<search::Hit>, error::Error>;
+    fn moderation(
+        &self,
+        channel: &str,
+        since: DateTime<Utc>,
+        until: DateTime<Utc>,
+    ) -> Result<Vec<search::Moderation>, error::Error>;
+    // UTC days with messages in `channel`, or only from `user` when given, newest first.
+    fn days(&self, channel: &str, user: Option<&str>) -> Result<Vec<NaiveDate>, error::Error>;
+}
+
+pub fn open(config: &config::Config) -> Result<Option<Arc<dyn Store>>, error::Error> {
+    match config.storage {
+        config::Storage::Postgres if config.postgres.is_empty() => Ok(None),
+        config::Storage::Postgres => Ok(Some(Arc::new(db::Postgres::new(&config.postgres)?))),
+        config::Storage::Sqlite => Ok(Some(Arc::new(sqlite::Sqlite::open(&config.sqlite)?))),
+    }
+}
+
+// Messages without a room id (e.g. from journals written before it was parsed) are stored with
+// a NULL reference rather than pointing at a channel that does not exist.
+pub const fn room_id(log: &message::Message) -> Option<i32> {
+    if log.room_id == 0 {
+        None
+    } else {
+        Some(log.room_id)
+    }
+}
+
+pub fn msg_id(log: &message::Message) -> Option<&str> {
+    if log.msg_id.is_empty() {
+        None
+    } else {
+        Some(&log.msg_id)
+    }
+}
+
+pub fn target_msg_id(log: &message::Message) -> Option<&str> {
+    if log.target_msg_id.is_empty() {
+        None
+    } else {
+        Some(&log.target_msg_id)
+    }
+}
+
+// Chat clears carry no user, and CLEARMSG only a login.
+pub const fn user_id(log: &message::Message) -> Option<i32> {
+    if log.user_id == 0 {
+        None
+    } else {
+        Some(log.user_id)
+    }
+}
+
+// Moderation events are stored in their own table; everything else goes to `chanlog`.
+pub fn split(logs: &[message::Message]) -> (Vec<message::Message>, Vec<message::Message>) {
+    logs.iter().cloned().partition(|x| !x.is_moderation())
+}
+
+pub type Seen<'a> = BTreeMap<i32, (&'a str, DateTime<Utc>, DateTime<Utc>)>;
+pub type Names<'a> = BTreeMap<(i32, &'a str, &'a str), (DateTime<Utc>, DateTime<Utc>)>;
+
+fn seen<'a>(entities: &mut Seen<'a>, id: i32, name: &'a str, timestamp: DateTime<Utc>) {
+    let entry = entities.entry(id).or_insert((name, timestamp, timestamp));
+
+    if timestamp >= entry.2 {
+        entry.0 = name;
+        entry.2 = timestamp;
+    }
+
+    entry.1 = entry.1.min(timestamp);
+}
+
+#[derive(Debug, Default)]
+pub struct Entities<'a> {
+    pub channels: Seen<'a>,
+    pub users: Seen<'a>,
+    pub names: Names<'a>,
+}
+
+// Collapses a batch into one entry per channel, user and (user, login, display name), ordered
+// by id so concurrent writers lock rows in the same order.
+pub fn entities(logs: &[message::Message]) -> Entities<'_> {
+    let mut entities = Entities::default();
+
+    for log in logs {
+        if let Some(room_id) = room_id(log) {
+            seen(
+                &mut entities.channels,
+                room_id,
+                log.target.trim_start_matches('#'),
+                log.timestamp,
+            );
+        }
+
+        seen(&mut entities.users, log.user_id, &log.username, log.timestamp);
+
+        let entry = entities
+            .names
+            .entry((log.user_id, &log.username, &log.display_name))
+            .or_insert((log.timestamp, log.timestamp));
+
+        entry.0 = entry.0.min(log.timestamp);
+        entry.1 = entry.1.max(log.timestamp);
+    }
+
+    entities
+}
+
+// Errors where the backend refused the batch itself; retrying those would never succeed.
+// Other Postgres errors, such as deadlocks, timeouts or a failover, are retried.
+fn is_rejected(e: &error::Error) -> bool {
+    match e {
+        // SQLSTATE classes 22 (data exception) and 23 (integrity constraint violation).
+        error::Error::Postgres(e) => e.code().map_or(false, |x| {
+            let code = x.code();
+
+            code.starts_with("22") || code.starts_with("23")
+        }),
+        error::Error::Sqlite(rusqlite::Error::SqliteFailure(e, _)) => matches!(
+            e.code,
+            rusqlite::ErrorCode::ConstraintViolation
+                | rusqlite::ErrorCode::TypeMismatch
+                | rusqlite::ErrorCode::TooBig
+        ),
+        _ => false,
+    }
+}
+
+// Upper bound on rows held in memory while the database is unreachable; anything beyond it
+// stays in its journal segment and is read back once the database recovers.
+const MAX_PENDING_ROWS: usize = 100_000;
+const MAX_ADMIN_ROWS: usize = 1_000;
+const MIN_BACKOFF: time::Duration = time::Duration::from_secs(1);
+const MAX_BACKOFF: time::Duration = time::Duration::from_secs(5 * 60);
+const IDLE_RESCAN: time::Duration = time::Duration::from_secs(30);
+
+pub static PENDING: AtomicUsize = AtomicUsize::new(0);
+pub static SPILLED: AtomicUsize = AtomicUsize::new(0);
+pub static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+enum Job {
+    Logs(Vec<message::Message>, path::PathBuf),
+    AdminLog(message::Message),
+}
+
+// All database writes go through a single thread that owns the retry queue.
+#[derive(Clone)]
+pub struct Writer {
+    tx: mpsc::Sender<Job>,
+    pub store: Arc<dyn Store>,
+}
+
+impl Writer {
+    pub fn spawn(store: Arc<dyn Store>) -> Self {
+        let (tx, rx) = mpsc::channel();
+        let writer_store = Arc::clone(&store);
+
+        thread::spawn(move || run_writer(&rx, writer_store.as_ref()));
+
+        Self { tx, store }
+    }
+
+    pub fn insert_admin_log(&self, log: message::Message) {
+        if self.tx.send(Job::AdminLog(log)).is_err() {
+            eprintln!("Database writer has stopped; admin log discarded");
+        }
+    }
+
+    pub fn insert_logs(
+        &self,
+        logs: std::vec::Vec<message::Message>,
+        segment: path::PathBuf,
+    ) -> Result<(), error::Error> {
+        PENDING.fetch_add(logs.len(), Ordering::Relaxed);
+
+        if self.tx.send(Job::Logs(logs, segment)).is_err() {
+            eprintln!("Database writer has stopped; batch left in journal");
+        }
+
+        Ok(())
+    }
+}
+
+fn run_writer(rx: &mpsc::Receiver<Job>, store: &dyn Store) {
+    let mut cache: HashMap<path::PathBuf, Vec<message::Message>> = HashMap::new();
+    let mut admin = Vec::new();
+    let mut backoff = MIN_BACKOFF;
+    let mut next_attempt = time::Instant::now();
+
+    loop {
+        let wait = next_attempt.saturating_duration_since(time::Instant::now());
+
+        let idle = cache.is_empty() && admin.is_empty();
+
+        match rx.recv_timeout(if idle { wait.max(IDLE_RESCAN) } else { wait }) {
+            Ok(Job::Logs(logs, segment)) => {
+                // A flush between sealing and sending may already have written the segment from
+                // disk; caching it then would keep it pending forever.
+                if !segment.exists() || PENDING.load(Ordering::Relaxed) > MAX_PENDING_ROWS {
+                    PENDING.fetch_sub(logs.len(), Ordering::Relaxed);
+                } else {
+                    cache.insert(segment, logs);
+                }
+            }
+            // Admin logs are also written to `logs/admin`, so the oldest are dropped first.
+            Ok(Job::AdminLog(log)) => {
+                if admin.len() >= MAX_ADMIN_ROWS {
+                    admin.remove(0);
+                    DROPPED.fetch_add(1, Ordering::Relaxed);
+                }
+
+                admin.push(log);
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        }
+
+        if time::Instant::now() < next_attempt {
+            continue;
+        }
+
+        let result = flush(store, &mut cache, &mut admin);
+
+        health::database(result.as_ref().map(|_| ()));
+
+        match result {
+            Ok(_) => backoff = MIN_BACKOFF,
+            Err(e) => {
+                eprintln!("Database write failed, retrying in {}s: {}", backoff.as_secs(), e);
+                next_attempt = time::Instant::now() + backoff;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+    }
+}
+
+// Writes admin logs, then every sealed journal segment oldest first, stopping at the first
+// connection failure so ordering is preserved across retries. Batches the database rejects are
+// dropped rather than retried.
+fn flush(
+    store: &dyn Store,
+    cache: &mut HashMap<path::PathBuf, Vec<message::Message>>,
+    admin: &mut Vec<message::Message>,
+) -> Result<(), error::Error> {
+    if !admin.is_empty() {
+        match store.write_admin_logs(admin) {
+            Ok(_) => admin.clear(),
+            Err(ref e) if is_rejected(e) => {
+                eprintln!("Rejected {} admin logs: {}", admin.len(), e);
+                metrics::WRITE_ERRORS.fetch_add(1, Ordering::Relaxed);
+                DROPPED.fetch_add(admin.len(), Ordering::Relaxed);
+                admin.clear();
+            }
+            Err(e) => {
+                metrics::WRITE_ERRORS.fetch_add(1, Ordering::Relaxed);
+                return Err(e);
+            }
+        }
+    }
+
+    let segments = journal::sealed()?;
+
+    SPILLED.store(segments.iter().filter(|x| !cache.contains_key(*x)).count(), Ordering::Relaxed);
+
+    for segment in segments {
+        let (logs, cached) = match cache.remove(&segment) {
+            Some(logs) => (logs, true),
+            None => (journal::read(&segment)?, false),
+        };
+
+        let started = time::Instant::now();
+
+        match store.write_logs(&logs) {
+            Ok(_) => {
+                metrics::batch(logs.len(), started.elapsed());
+                journal::remove(&segment)?;
+            }
+            Err(ref e) if is_rejected(e) => {
+                eprintln!("Rejected journal segment {}: {}", segment.display(), e);
+                metrics::WRITE_ERRORS.fetch_add(1, Ordering::Relaxed);
+                DROPPED.fetch_add(logs.len(), Ordering::Relaxed);
+                journal::reject(&segment)?;
+            }
+            Err(e) => {
+                metrics::WRITE_ERRORS.fetch_add(1, Ordering::Relaxed);
+
+                if cached {
+                    cache.insert(segment, logs);
+                }
+
+                return Err(e);
+            }
+        }
+
+        if cached {
+            PENDING.fetch_sub(logs.len(), Ordering::Relaxed);
+        } else {
+            SPILLED.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    Ok(())
+}
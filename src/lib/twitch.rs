@@ -0,0 +1,360 @@
+use std::{fmt, io, str, time};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, ReadHalf};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_native_tls::{native_tls, TlsConnector};
+
+use crate::lib::error;
+
+// Twitch pings about every five minutes; a connection quiet for longer than `PING_INTERVAL` is
+// pinged by the bot and dropped if nothing arrives within `PING_TIMEOUT`.
+const PING_INTERVAL: time::Duration = time::Duration::from_secs(180);
+const PING_TIMEOUT: time::Duration = time::Duration::from_secs(20);
+
+// One IRC line. Tag values are kept escaped, as Twitch sent them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Message {
+    pub tags: Vec<(String, String)>,
+    pub prefix: Option<String>,
+    pub command: String,
+    pub args: Vec<String>,
+    pub trailing: Option<String>,
+}
+
+impl str::FromStr for Message {
+    type Err = error::Error;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let mut rest = line.trim_end_matches(['\r', '\n']);
+        let invalid = || error::Error::Irc(format!("Invalid message: {}", line));
+
+        let tags = match rest.strip_prefix('@') {
+            Some(tagged) => {
+                let (tags, after) = tagged.split_once(' ').ok_or_else(invalid)?;
+
+                rest = after;
+                tags.split(';')
+                    .filter(|x| !x.is_empty())
+                    .map(|x| {
+                        let (key, value) = x.split_once('=').unwrap_or((x, ""));
+
+                        (key.to_string(), value.to_string())
+                    })
+                    .collect()
+            }
+            None => Vec::new(),
+        };
+
+        let prefix = match rest.strip_prefix(':') {
+            Some(prefixed) => {
+                let (prefix, after) = prefixed.split_once(' ').ok_or_else(invalid)?;
+
+                rest = after;
+                Some(prefix.to_string())
+            }
+            None => None,
+        };
+
+        let trailing = rest.find(" :").map(|i| {
+            let trailing = rest[i + 2..].to_string();
+
+            rest = &rest[..i];
+            trailing
+        });
+
+        let mut words = rest.split(' ').filter(|x| !x.is_empty());
+        let command = words.next().ok_or_else(invalid)?.to_string();
+
+        Ok(Self { tags, prefix, command, args: words.map(String::from).collect(), trailing })
+    }
+}
+
+// The line as sent, without the line ending.
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.tags.is_empty() {
+            let tags: Vec<String> =
+                self.tags.iter().map(|(key, value)| format!("{}={}", key, value)).collect();
+
+            write!(f, "@{} ", tags.join(";"))?;
+        }
+
+        if let Some(prefix) = &self.prefix {
+            write!(f, ":{} ", prefix)?;
+        }
+
+        write!(f, "{}", self.command)?;
+
+        for arg in &self.args {
+            write!(f, " {}", arg)?;
+        }
+
+        if let Some(trailing) = &self.trailing {
+            write!(f, " :{}", trailing)?;
+        }
+
+        Ok(())
+    }
+}
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+// Sends lines to Twitch. Clones share the connection, and sending never waits on the socket.
+#[derive(Clone)]
+pub struct Client {
+    tx: mpsc::UnboundedSender<String>,
+}
+
+impl Client {
+    pub fn send(&self, line: &str) -> Result<(), error::Error> {
+        self.tx.send(line.to_string()).map_err(|_| {
+            io::Error::new(io::ErrorKind::BrokenPipe, "Connection to Twitch has closed").into()
+        })
+    }
+
+    pub fn send_join(&self, channel: &str) -> Result<(), error::Error> {
+        self.send(&format!("JOIN {}", channel))
+    }
+
+    pub fn send_part(&self, channel: &str) -> Result<(), error::Error> {
+        self.send(&format!("PART {}", channel))
+    }
+}
+
+pub struct Reader {
+    lines: tokio::io::Lines<BufReader<ReadHalf<Box<dyn Stream>>>>,
+    client: Client,
+    ping_interval: time::Duration,
+    ping_timeout: time::Duration,
+}
+
+impl Reader {
+    async fn line(&mut self) -> Result<Option<String>, error::Error> {
+        if let Ok(line) = tokio::time::timeout(self.ping_interval, self.lines.next_line()).await {
+            return Ok(line?);
+        }
+
+        self.client.send("PING :tmi.twitch.tv")?;
+
+        match tokio::time::timeout(self.ping_timeout, self.lines.next_line()).await {
+            Ok(line) => Ok(line?),
+            Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "Ping timeout").into()),
+        }
+    }
+
+    // The next line from Twitch. PINGs are answered here and still returned, so callers can
+    // tell the connection is alive. A `RECONNECT` is returned like any other line; Twitch keeps
+    // the connection open for a while after sending one.
+    pub async fn next(&mut self) -> Result<Message, error::Error> {
+        loop {
+            let line = self.line().await?.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed by Twitch")
+            })?;
+
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let message: Message = line.parse()?;
+
+            if message.command == "PING" {
+                let server = message
+                    .trailing
+                    .as_deref()
+                    .or_else(|| message.args.first().map(String::as_str));
+
+                self.client.send(&format!("PONG :{}", server.unwrap_or("tmi.twitch.tv")))?;
+            }
+
+            return Ok(message);
+        }
+    }
+}
+
+// Opens a connection, over TLS unless `tls` is false. Lines given to the client are written by
+// a task that lives as long as any clone of it.
+pub async fn connect(server: &str, port: u16, tls: bool) -> Result<(Client, Reader), error::Error> {
+    let tcp = TcpStream::connect((server, port)).await?;
+    let stream: Box<dyn Stream> = if tls {
+        let connector = TlsConnector::from(native_tls::TlsConnector::new()?);
+
+        Box::new(connector.connect(server, tcp).await?)
+    } else {
+        Box::new(tcp)
+    };
+
+    let (read, mut write) = tokio::io::split(stream);
+    let (tx, mut rx) = mpsc::unbounded_channel::<String>();
+
+    tokio::spawn(async move {
+        while let Some(line) = rx.recv().await {
+            let sent = write.write_all(format!("{}\r\n", line).as_bytes()).await;
+
+            if sent.is_err() || write.flush().await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let client = Client { tx };
+    let reader = Reader {
+        lines: BufReader::new(read).lines(),
+        client: client.clone(),
+        ping_interval: PING_INTERVAL,
+        ping_timeout: PING_TIMEOUT,
+    };
+
+    Ok((client, reader))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    const PRIVMSG: &str = "@badge-info=subscriber/3;display-name=Bob;id=m-1;room-id=5;\
+        tmi-sent-ts=1791626400000;user-id=7 :bob!bob@bob.tmi.twitch.tv PRIVMSG #chan :hello there";
+
+    // A fake server on a free local port, and a connected client and reader.
+    async fn fake() -> (TcpStream, Client, Reader) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (connected, accepted) =
+            tokio::join!(connect("127.0.0.1", port, false), listener.accept());
+        let (client, reader) = connected.unwrap();
+
+        (accepted.unwrap().0, client, reader)
+    }
+
+    async fn received(server: &mut TcpStream, expected: &str) {
+        let mut buffer = vec![0; expected.len()];
+
+        server.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(String::from_utf8(buffer).unwrap(), expected);
+    }
+
+    #[test]
+    fn parses_tagged_lines() {
+        let message: Message = PRIVMSG.parse().unwrap();
+
+        assert_eq!(message.command, "PRIVMSG");
+        assert_eq!(message.prefix.as_deref(), Some("bob!bob@bob.tmi.twitch.tv"));
+        assert_eq!(message.args, vec!["#chan"]);
+        assert_eq!(message.trailing.as_deref(), Some("hello there"));
+        assert_eq!(message.tags[1], (String::from("display-name"), String::from("Bob")));
+        assert_eq!(message.to_string(), PRIVMSG);
+    }
+
+    #[test]
+    fn keeps_tag_values_escaped() {
+        let line = "@login=bob;system-msg=bob\\ssubscribed :tmi.twitch.tv USERNOTICE #chan";
+        let message: Message = line.parse().unwrap();
+
+        assert_eq!(message.tags[1].1, "bob\\ssubscribed");
+        assert_eq!(message.trailing, None);
+        assert_eq!(message.to_string(), line);
+    }
+
+    #[test]
+    fn rejects_lines_without_a_command() {
+        assert!("@id=1".parse::<Message>().is_err());
+        assert!(":tmi.twitch.tv".parse::<Message>().is_err());
+    }
+
+    #[tokio::test]
+    async fn sends_lines() {
+        let (mut server, client, _reader) = fake().await;
+
+        client.send("PASS oauth:secret").unwrap();
+        client.send_join("#chan").unwrap();
+        client.send("PRIVMSG #chan :/w bob hi").unwrap();
+        client.send_part("#chan").unwrap();
+
+        received(&mut server, "PASS oauth:secret\r\nJOIN #chan\r\n").await;
+        received(&mut server, "PRIVMSG #chan :/w bob hi\r\nPART #chan\r\n").await;
+    }
+
+    #[tokio::test]
+    async fn reads_messages() {
+        let (mut server, _client, mut reader) = fake().await;
+
+        server
+            .write_all(format!("{}\r\n\r\n:tmi.twitch.tv 001 bot\r\n", PRIVMSG).as_bytes())
+            .await
+            .unwrap();
+
+        let message = reader.next().await.unwrap();
+
+        assert_eq!(message.trailing.as_deref(), Some("hello there"));
+        assert_eq!(reader.next().await.unwrap().command, "001");
+    }
+
+    #[tokio::test]
+    async fn answers_pings() {
+        let (mut server, _client, mut reader) = fake().await;
+
+        server.write_all(b"PING :tmi.twitch.tv\r\n").await.unwrap();
+
+        assert_eq!(reader.next().await.unwrap().command, "PING");
+        received(&mut server, "PONG :tmi.twitch.tv\r\n").await;
+    }
+
+    #[tokio::test]
+    async fn pings_quiet_connections() {
+        let (mut server, _client, mut reader) = fake().await;
+
+        reader.ping_interval = time::Duration::from_millis(50);
+        reader.ping_timeout = time::Duration::from_secs(5);
+
+        let (message, ()) = tokio::join!(reader.next(), async {
+            received(&mut server, "PING :tmi.twitch.tv\r\n").await;
+            server
+                .write_all(b":tmi.twitch.tv PONG tmi.twitch.tv :tmi.twitch.tv\r\n")
+                .await
+                .unwrap();
+        });
+
+        assert_eq!(message.unwrap().command, "PONG");
+    }
+
+    #[tokio::test]
+    async fn drops_unanswered_pings() {
+        let (_server, _client, mut reader) = fake().await;
+
+        reader.ping_interval = time::Duration::from_millis(50);
+        reader.ping_timeout = time::Duration::from_millis(50);
+
+        match reader.next().await {
+            Err(error::Error::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::TimedOut),
+            other => panic!("Expected a ping timeout, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_on_after_reconnect() {
+        let (mut server, _client, mut reader) = fake().await;
+
+        server
+            .write_all(format!(":tmi.twitch.tv RECONNECT\r\n{}\r\n", PRIVMSG).as_bytes())
+            .await
+            .unwrap();
+
+        assert_eq!(reader.next().await.unwrap().command, "RECONNECT");
+        assert_eq!(reader.next().await.unwrap().command, "PRIVMSG");
+    }
+
+    #[tokio::test]
+    async fn fails_when_closed() {
+        let (server, _client, mut reader) = fake().await;
+
+        drop(server);
+
+        match reader.next().await {
+            Err(error::Error::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof),
+            other => panic!("Expected the connection to close, got {:?}", other),
+        }
+    }
+}
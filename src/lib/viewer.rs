@@ -0,0 +1,192 @@
+use chrono::prelude::*;
+
+use crate::lib::{error, http, search};
+
+// Kept inline so the viewer needs nothing besides the binary.
+const STYLE: &str = "body { font-family: sans-serif; margin: 1em 2em; }
+table { border-collapse: collapse; width: 100%; }
+td { padding: 2px 6px; vertical-align: top; border-bottom: 1px solid #eee; }
+td.time { white-space: nowrap; color: #666; font-family: monospace; }
+td.user { white-space: nowrap; font-weight: bold; }
+tr.notice td { color: #555; font-style: italic; }
+tr.event td { color: #a00; }
+tr.deleted td.message { text-decoration: line-through; }
+tr.deleted { background: #fde8e8; }
+tr.timedout { background: #fff1db; }
+tr.cleared { background: #eef; }
+tr#jump { outline: 2px solid #36c; }
+form { margin: 1em 0; }";
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn page(title: &str, body: &str) -> http::Reply {
+    http::Reply::html(format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{}</title><style>{}</style></head>\
+            <body>{}</body></html>\n",
+        escape(title),
+        STYLE,
+        body
+    ))
+}
+
+pub fn channels(source: &http::Source) -> Result<http::Reply, error::Error> {
+    let links: Vec<String> = http::channel_names(source)?
+        .iter()
+        .map(|x| format!("<li><a href=\"/view/{}\">#{}</a></li>", escape(x), escape(x)))
+        .collect();
+
+    Ok(page("Channels", &format!("<h1>Channels</h1><ul>{}</ul>", links.concat())))
+}
+
+enum Row<'a> {
+    Message(&'a search::Hit),
+    Event(&'a search::Moderation),
+}
+
+impl Row<'_> {
+    const fn timestamp(&self) -> DateTime<Utc> {
+        match self {
+            Self::Message(hit) => hit.timestamp,
+            Self::Event(event) => event.timestamp,
+        }
+    }
+
+    fn username(&self) -> &str {
+        match self {
+            Self::Message(hit) => &hit.username,
+            Self::Event(event) => &event.username,
+        }
+    }
+}
+
+// The CSS class and tooltip for a message that a later event removed from chat. Messages read
+// from text logs carry no id, so deletions are matched on author and text instead.
+fn status(hit: &search::Hit, events: &[search::Moderation]) -> Option<(&'static str, String)> {
+    events.iter().filter(|x| x.timestamp >= hit.timestamp).find_map(|event| {
+        let deleted = if hit.msg_id.is_empty() {
+            event.username == hit.username && event.user_msg == hit.user_msg
+        } else {
+            event.target_msg_id == hit.msg_id
+        };
+
+        match event.command.as_str() {
+            "CLEARMSG" if deleted => Some(("deleted", String::from("Deleted"))),
+            "CLEARCHAT" if event.username == hit.username => Some(("timedout", event.describe())),
+            "CLEARCHAT" if event.username.is_empty() => Some(("cleared", event.describe())),
+            _ => None,
+        }
+    })
+}
+
+// One UTC day of a channel with a day picker, a user filter and jump-to-time. Messages that
+// were deleted, or whose author was timed out or banned, are highlighted.
+pub fn day(
+    source: &http::Source,
+    name: &str,
+    request: &http::Request,
+) -> Result<http::Reply, error::Error> {
+    let since = match http::date(request) {
+        Ok(since) => since,
+        Err(e) => return Ok(http::Reply::bad_request(&e)),
+    };
+    let until = since + chrono::Duration::days(1);
+    let channel = match http::channel(name) {
+        Ok(channel) => channel,
+        Err(e) => return Ok(http::Reply::bad_request(&e)),
+    };
+    let user = request.param("user").map(str::to_lowercase);
+    let time = request.param("time").and_then(|x| NaiveTime::parse_from_str(x, "%H:%M").ok());
+
+    let hits = http::day(source, &channel, since, until)?;
+    let events = http::moderation(source, &channel, since, until)?;
+
+    let mut rows: Vec<Row> = hits.iter().map(Row::Message).collect();
+
+    rows.extend(events.iter().map(Row::Event));
+    rows.sort_by_key(Row::timestamp);
+
+    if let Some(user) = &user {
+        rows.retain(|x| x.username() == user);
+    }
+
+    let jump = time.and_then(|time| rows.iter().position(|x| x.timestamp().time() >= time));
+    let mut table = Vec::new();
+
+    for (i, row) in rows.iter().enumerate() {
+        let id = if Some(i) == jump { " id=\"jump\"" } else { "" };
+        let time = row.timestamp().format("%H:%M:%S");
+
+        let (class, title, user, message) = match row {
+            Row::Message(hit) => {
+                let (class, title) = status(hit, &events).unwrap_or(("", String::new()));
+                let (class, message) = if hit.system_msg.is_empty() {
+                    (class, hit.user_msg.clone())
+                } else {
+                    ("notice", format!("{} {}", hit.system_msg, hit.user_msg))
+                };
+
+                (class, title, hit.username.as_str(), message)
+            }
+            Row::Event(event) => ("event", String::new(), "", event.describe()),
+        };
+
+        table.push(format!(
+            "<tr class=\"{}\" title=\"{}\"{}><td class=\"time\">{}</td><td class=\"user\">{}</td>\
+                <td class=\"message\">{}</td></tr>",
+            class,
+            escape(&title),
+            id,
+            time,
+            escape(user),
+            escape(&message)
+        ));
+    }
+
+    let day = since.format("%Y-%m-%d");
+    // The user is free text, so it is encoded for the query string before the page escapes it.
+    let user_query: String =
+        form_urlencoded::byte_serialize(user.as_deref().unwrap_or("").as_bytes()).collect();
+    let link = |day: DateTime<Utc>| {
+        format!(
+            "/view/{}?date={}&amp;user={}",
+            escape(channel.trim_start_matches('#')),
+            day.format("%Y-%m-%d"),
+            escape(&user_query)
+        )
+    };
+
+    let body = format!(
+        "<p><a href=\"/\">Channels</a></p><h1>{channel} on {day}</h1>\
+            <form method=\"get\">\
+                <label>Day <input type=\"date\" name=\"date\" value=\"{day}\"></label> \
+                <label>User <input type=\"text\" name=\"user\" value=\"{user}\"></label> \
+                <label>Jump to <input type=\"time\" name=\"time\" value=\"{time}\"></label> \
+                <button type=\"submit\">Show</button> \
+                <a href=\"{previous}\">Previous day</a> <a href=\"{next}\">Next day</a>\
+            </form>\
+            <p>Messages: {count}. Times are UTC. Deleted messages are struck through; messages from \
+                users who were later timed out or banned are shaded.</p>\
+            <table>{table}</table>{script}",
+        channel = escape(&channel),
+        day = day,
+        user = escape(user.as_deref().unwrap_or("")),
+        time = time.map(|x| x.format("%H:%M").to_string()).unwrap_or_default(),
+        previous = link(since - chrono::Duration::days(1)),
+        next = link(until),
+        count = rows.iter().filter(|x| matches!(x, Row::Message(_))).count(),
+        table = table.concat(),
+        script = if jump.is_some() {
+            "<script>document.getElementById('jump').scrollIntoView();</script>"
+        } else {
+            ""
+        }
+    );
+
+    Ok(page(&format!("{} on {}", channel, day), &body))
+}
@@ -9,104 +9,144 @@ extern crate serde_derive;
 
 mod lib;
 
-use indicatif::ProgressIterator;
-use irc::client::prelude::*;
-use lib::{config, db, file, message};
+use lib::{config, db, file, health, http, index, metrics, pipeline, search, shard, sink, store};
+use std::sync::atomic::Ordering;
 use std::sync::{Arc, Mutex};
-use std::{thread, time};
+use std::{env, thread, time};
 
 fn main() {
-    let mut count = 0;
+    let config = config::Config::load().expect("Unable to load config file");
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    if args.first().map(String::as_str) == Some("bench") {
+        let rows = args.get(1).and_then(|x| x.parse().ok()).unwrap_or(10_000);
+
+        return bench(&config, rows);
+    }
+
+    if args.first().map(String::as_str) == Some("search") {
+        return search(&config, &args[1..]);
+    }
+
+    let writer = store::open(&config).expect("Unable to open database").map(store::Writer::spawn);
+
+    if !config.http.is_empty() {
+        let source = writer
+            .as_ref()
+            .map_or(http::Source::Files, |x| http::Source::Store(Arc::clone(&x.store)));
+
+        http::spawn(&config.http, &source).expect("Unable to start HTTP server");
+    }
+    let runtime = tokio::runtime::Runtime::new().expect("Unable to start async runtime");
+    let mut backoff = shard::MIN_BACKOFF;
+
+    health::start();
+
+    // Dropped connections are replaced within `run`, so it only returns when the session can't
+    // go on, such as when Twitch can't be reached at all. Retried without limit, with the delay
+    // reset after a session that ran for a while.
+    loop {
+        let started = time::Instant::now();
+        let result = run(&runtime, writer.as_ref());
+
+        metrics::RECONNECTS.fetch_add(1, Ordering::Relaxed);
+
+        if started.elapsed() > shard::MAX_BACKOFF {
+            backoff = shard::MIN_BACKOFF;
+        }
+
+        if let Err(e) = result {
+            eprintln!("{}", e);
+        }
+
+        println!("Reconnecting in {}s...", backoff.as_secs());
+        thread::sleep(backoff);
+        backoff = (backoff * 2).min(shard::MAX_BACKOFF);
+    }
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn bench(config: &config::Config, rows: usize) {
+    let postgres =
+        db::Postgres::new(&config.postgres).expect("Unable to parse postgres connection string");
 
-    while let Err(e) = run() {
-        eprintln!("{}", e);
+    store::Store::migrate(&postgres).expect("Unable to migrate database");
 
-        count += 1;
-        thread::sleep(time::Duration::from_secs(30));
+    let (insert, copy) = db::bench(&postgres.pool, rows).expect("Unable to run benchmark");
 
-        if count == 3 {
-            panic!("Failed to reconnect within three attempts");
+    for (name, elapsed) in &[("INSERT", insert), ("COPY", copy)] {
+        println!(
+            "{:>6}: {} rows in {:.3}s ({:.0} rows/s)",
+            name,
+            rows,
+            elapsed.as_secs_f64(),
+            rows as f64 / elapsed.as_secs_f64()
+        );
+    }
+}
+
+// Prints every match, newest first, up to `SEARCH_LIMIT`. Without a database, matches come
+// from the text log index and are shown with `SEARCH_CONTEXT` lines on either side.
+const SEARCH_LIMIT: u32 = 100;
+const SEARCH_CONTEXT: usize = 2;
+
+fn search(config: &config::Config, args: &[String]) {
+    let query = search::Query::parse(args).unwrap_or_else(|e| panic!("{}", e));
+
+    // Searching leaves the schema to the bot, so it never migrates a database in use.
+    if let Some(store) = store::open(config).expect("Unable to open database") {
+        for hit in store.search(&query, SEARCH_LIMIT).expect("Unable to search messages") {
+            println!("{}", hit.line());
+        }
+
+        return;
+    }
+
+    for (i, found) in
+        index::search(&query, SEARCH_LIMIT).expect("Unable to search index").iter().enumerate()
+    {
+        if i > 0 {
+            println!("--");
+        }
+
+        println!("{}", found.path);
+
+        match found.context(SEARCH_CONTEXT) {
+            Ok(lines) => lines.iter().for_each(|x| println!("{}", x)),
+            Err(_) => println!("> {}", found.hit.line()),
         }
     }
 }
 
-fn run() -> Result<(), lib::error::Error> {
+fn run(
+    runtime: &tokio::runtime::Runtime,
+    writer: Option<&store::Writer>,
+) -> Result<(), lib::error::Error> {
     let config = config::Config::load().expect("Unable to load config file");
 
     file::create_dirs(&config.channels).expect("Unable to create log directories");
 
-    let postgres = {
-        if config.postgres.is_empty() {
-            false
-        } else {
-            match db::create_tables() {
+    let database = {
+        if let Some(writer) = writer {
+            match writer.store.migrate() {
                 Ok(_) => true,
                 Err(e) => {
                     eprintln!("Logging to database is not enabled: {}", e);
                     false
                 }
             }
+        } else {
+            false
         }
     };
-    let mut reactor = IrcReactor::new()?;
-    let client = reactor.prepare_client_and_connect(&Config {
-        nickname: Some(config.nickname.to_owned()),
-        server: Some(config.server.to_owned()),
-        ..Config::default()
-    })?;
-    let count = config.channels.len();
-    let bot_state = Arc::new(Mutex::new(config::State::new(count, postgres)));
-    let v = Arc::new(Mutex::new(Vec::new()));
-
-    client.send(Command::Raw("PASS".to_owned(), vec![config.oauth.to_owned()], None))?;
-    client.send(Command::Raw("NICK".to_owned(), vec![config.nickname.to_owned()], None))?;
-    client.send(Command::Raw("CAP REQ :twitch.tv/tags".to_owned(), vec![], None))?;
-    client.send(Command::Raw("CAP REQ :twitch.tv/commands".to_owned(), vec![], None))?;
-
-    // The rate limit to join channels is 50 every 15 seconds.
-    for channel in config.channels.iter().progress() {
-        client.send_join(channel)?;
-        thread::sleep(time::Duration::from_millis(320));
-    }
-
-    match count {
-        0 => println!("Bot is now logging 0 channels..."),
-        1 => println!("Bot is now logging 1 channel..."),
-        _ => println!("Bot is now logging {} channels...", count),
-    };
+    let bot_state = config::State::new(config.channels.len(), database);
 
-    reactor.register_client_with_handler(client, move |client, raw_msg| {
-        let parsed_msg = message::Message::parse_msg(&raw_msg).expect("Unable to parse message");
-        let bot_state = bot_state.lock().expect("Unable to acquire bot state mutex");
-        let mut v = v.lock().expect("Unable to acquire channel mutex");
-
-        if !parsed_msg.command.is_empty() {
-            if parsed_msg.command == "WHISPER" {
-                lib::commands::parse_cmd(client, bot_state, &parsed_msg)
-                    .expect("Unable to save admin message");
-            } else if !bot_state.paused {
-                file::Logger::save_msg_txt(&parsed_msg, bot_state.buffer)
-                    .expect("Unable to save message");
-
-                if bot_state.postgres {
-                    v.push(parsed_msg);
-
-                    if v.len() >= bot_state.buffer {
-                        match db::insert_logs(v.to_owned()) {
-                            Ok(_) => {}
-                            Err(e) => eprintln!("{}", e),
-                        }
-
-                        v.clear();
-                    };
-                }
-            }
-        };
+    metrics::publish(bot_state);
 
-        Ok(())
-    });
+    let bot_state = Arc::new(Mutex::new(bot_state));
 
-    reactor.run()?;
+    let writer = if database { writer.cloned() } else { None };
+    let sinks = sink::open(&config, writer.clone())?;
 
-    Ok(())
+    runtime.block_on(pipeline::run(config, bot_state, sinks, writer))
 }